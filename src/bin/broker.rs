@@ -0,0 +1,35 @@
+use std::{env, sync::{Arc, Mutex}};
+use stock_simulation::{broker, journal, latency, metrics, session};
+use stock_simulation::config::Config;
+use stock_simulation::logging::Component;
+use stock_simulation::portfolio::Portfolio;
+use stock_simulation::transport::{Connector, Result};
+
+fn main() -> Result<()> {
+    // `--id <n>` picks which broker this process is, any number of them can run
+    let args: Vec<String> = env::args().collect();
+    let broker_no = args.iter().position(|arg| arg == "--id").and_then(|i| args.get(i+1))
+        .and_then(|id| id.parse::<i8>().ok()).filter(|id| *id > 0)
+        .unwrap_or_else(|| panic!("Usage: broker --id <n> [--config <path>] [--journal <path>] [--stats-out <path>] [--metrics <addr>] [--log-format <f>] [--log-level <l>] [--log-filter <spec>], with n from 1 to 127"));
+    let config = Config::from_args();
+    // Ctrl-C or SIGTERM makes the broker flush, report and deregister before closing
+    session::install_signal_handler();
+    // `--journal <path>` records this broker's events, as JSON lines for `.jsonl` and binary otherwise
+    if let Some(path) = args.iter().position(|arg| arg == "--journal").and_then(|i| args.get(i+1)){
+        journal::open(path).unwrap_or_else(|err| panic!("Invalid --journal: {}", err));
+    }
+    // `--metrics <addr>` serves Prometheus metrics, use a different port per broker
+    if let Some(addr) = args.iter().position(|arg| arg == "--metrics").and_then(|i| args.get(i+1)){
+        metrics::serve(addr).unwrap_or_else(|err| panic!("Invalid --metrics: {}", err));
+    }
+    // `--stats-out <path>` exports the latency summary as JSON, or CSV for a `.csv` path
+    let stats_out = args.iter().position(|arg| arg == "--stats-out").and_then(|i| args.get(i+1));
+    // Open connection for this broker, every message is stamped for the latency summary
+    let transport = Connector::Amqp(config.amqp_url.clone()).connect()?;
+    // this broker's own view, cash is only held against the orders it works
+    let portfolio = Arc::new(Mutex::new(Portfolio::new(config.initial_cash)));
+    broker::run(&*transport, &config, broker_no, &portfolio)?;
+    transport.close()?;
+    latency::finish(Component::Broker(broker_no), stats_out.map(String::as_str));
+    Ok(())
+}
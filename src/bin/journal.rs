@@ -0,0 +1,57 @@
+use std::{collections::BTreeMap, env};
+use stock_simulation::journal::{self, Event};
+
+// Rebuild the exchange's & brokers' state from one or more journals and print it
+fn main(){
+    let paths: Vec<String> = env::args().skip(1).collect();
+    if paths.is_empty(){
+        panic!("Usage: journal <path> [<path>...], e.g. the exchange's journal and each broker's");
+    }
+    let journals = paths.iter().map(|path| journal::read(path).unwrap_or_else(|err| panic!("Invalid journal: {}", err))).collect();
+    let entries = journal::merge(journals);
+
+    // how many of each event
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for entry in entries.iter(){
+        let name = match entry.event{
+            Event::StockListed{..} => "StockListed",
+            Event::OrderReceived{..} => "OrderReceived",
+            Event::OrderSubmitted{..} => "OrderSubmitted",
+            Event::Fill{..} => "Fill",
+            Event::VolumeAdded{..} => "VolumeAdded",
+            Event::NewsReleased{..} => "NewsReleased",
+            Event::PriceChanged{..} => "PriceChanged",
+            Event::ListPriceUpdated{..} => "ListPriceUpdated",
+            Event::PositionAdded{..} => "PositionAdded",
+            Event::PositionReduced{..} => "PositionReduced",
+            Event::SellTriggered{..} => "SellTriggered",
+            Event::PositionsFlushed{..} => "PositionsFlushed",
+            Event::SessionEnded{..} => "SessionEnded",
+            Event::MarketDataPublished{..} => "MarketDataPublished",
+            Event::OrderNotice{..} => "OrderNotice",
+            Event::OrderControlReceived{..} => "OrderControlReceived",
+            Event::CancelSubmitted{..} => "CancelSubmitted",
+            Event::LevelsAmended{..} => "LevelsAmended",
+            Event::HighWaterRaised{..} => "HighWaterRaised",
+        };
+        *counts.entry(name.to_string()).or_insert(0)+=1;
+    }
+    println!("Journal: {} events", entries.len());
+    for (name, count) in counts.iter(){
+        println!("  {:<20} {}", name, count);
+    }
+
+    let state = journal::rebuild(&entries);
+    println!("Stock list:");
+    for stock in state.stock_list.iter(){
+        println!("  {:<8} {:.2}", stock.name, stock.value);
+    }
+    println!("Stock profiles:");
+    for p in state.profiles.iter(){
+        println!("  {:<8} price {:.2}  buy vol {}  sold vol {}", p.name, p.cur_price, p.buy_vol, p.sold_vol);
+    }
+    println!("Purchase history:");
+    for d in state.purchase_history.iter(){
+        println!("  Broker {} User {} [{}] x{}  take profit {:.2}  cut loss {:.2}", d.broker_no, d.id, d.stock_name, d.num_stock, d.take_profit, d.cut_loss);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{collections::BTreeSet, env, sync::{Arc, Mutex}, thread};
+use stock_simulation::{broker, journal, latency, logging, replay, session};
+use stock_simulation::logging::Component;
+use stock_simulation::config::Config;
+use stock_simulation::portfolio::Portfolio;
+use stock_simulation::transport::{Connector, MemoryTransport};
+
+const USAGE: &str = "Usage: replay <journal> [<journal>...] [--speed <x>|max] [--broker <n>] [--in-memory] [--journal <path>] [--stats-out <path>] [--config <path>]";
+
+fn main(){
+    // journals are the arguments that aren't flags or their values
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut paths: Vec<String> = Vec::new();
+    let mut speed = 1.0;
+    let mut only: BTreeSet<i8> = BTreeSet::new();
+    let mut in_memory = false;
+    let mut stats_out: Option<String> = None;
+    let mut i = 0;
+    while i < args.len(){
+        let value = args.get(i+1);
+        match args[i].as_str(){
+            // `--speed 10` replays ten times faster, `max` without any gaps
+            "--speed" => {
+                speed = match value.map(String::as_str){
+                    Some("max") => f64::INFINITY,
+                    Some(x) => x.parse::<f64>().ok().filter(|x| *x > 0.0).unwrap_or_else(|| panic!("Invalid --speed: {}", x)),
+                    None => panic!("{}", USAGE),
+                };
+                i+=1;
+            }
+            // `--broker <n>` only replays the orders broker n received, can be repeated
+            "--broker" => {
+                only.insert(value.and_then(|n| n.parse::<i8>().ok()).filter(|n| *n > 0).unwrap_or_else(|| panic!("{}", USAGE)));
+                i+=1;
+            }
+            // `--in-memory` runs the brokers in this process, no RabbitMQ server needed
+            "--in-memory" => in_memory = true,
+            // `--journal <path>` records what the replayed brokers did, to compare with the original
+            "--journal" => {
+                let path = value.unwrap_or_else(|| panic!("{}", USAGE));
+                journal::open(path).unwrap_or_else(|err| panic!("Invalid --journal: {}", err));
+                i+=1;
+            }
+            // `--stats-out <path>` exports the latency summary as JSON, or CSV for a `.csv` path
+            "--stats-out" => {
+                stats_out = Some(value.unwrap_or_else(|| panic!("{}", USAGE)).clone());
+                i+=1;
+            }
+            "--config" | "--log-format" | "--log-level" | "--log-filter" => i+=1, // read by Config::from_args
+            flag if flag.starts_with("--") => panic!("{}", USAGE),
+            path => paths.push(path.to_string()),
+        }
+        i+=1;
+    }
+    if paths.is_empty(){panic!("{}", USAGE);}
+    let config = Arc::new(Config::from_args());
+    // Ctrl-C stops the replay, the brokers then shut down as usual
+    session::install_signal_handler();
+
+    let journals = paths.iter().map(|path| journal::read(path).unwrap_or_else(|err| panic!("Invalid journal: {}", err))).collect();
+    let entries = journal::merge(journals);
+    let brokers = if only.is_empty(){replay::brokers_in(&entries)}else{only};
+    let pace = if speed.is_infinite(){"max".to_string()}else{format!("{}x", speed)};
+    logging::info(Component::Named("Replay"), format!("{} events for broker(s) {:?} at {} speed", entries.len(), brokers, pace))
+        .field("events", entries.len()).field("brokers", &brokers).field("speed", &pace).emit();
+
+    let connector = if in_memory{
+        Connector::Memory(MemoryTransport::new())
+    }else{
+        Connector::Amqp(config.amqp_url.clone())
+    };
+
+    // Brokers threads, otherwise start them with `cargo run --bin broker -- --id <n>`
+    let mut handles = Vec::new();
+    if in_memory{
+        // one portfolio, as in the simulation's own process
+        let portfolio = Arc::new(Mutex::new(Portfolio::shared(config.initial_cash)));
+        for broker_no in brokers.iter().copied(){
+            let connector_brk = connector.clone();
+            let config_brk = Arc::clone(&config);
+            let portfolio = Arc::clone(&portfolio);
+            handles.push(thread::spawn(move||{
+                let transport = connector_brk.connect().expect("Failed to open connection");
+                broker::run(&*transport, &config_brk, broker_no, &portfolio)
+                    .unwrap_or_else(|err| logging::error(Component::Broker(broker_no), format!("error: {:?}", err)).emit());
+            }));
+        }
+    }
+
+    let transport = connector.connect().expect("Failed to open connection");
+    replay::run(&*transport, &config, &entries, speed, &brokers).unwrap_or_else(|err| logging::error(Component::Named("Replay"), format!("error: {:?}", err)).emit());
+    // the brokers stop on their own once the messages dry up
+    for handle in handles{
+        handle.join().unwrap_or_else(|_| logging::error(Component::Named("Replay"), "a broker thread panicked").emit());
+    }
+    transport.close().unwrap_or_else(|err| logging::error(Component::Named("Replay"), format!("Error closing connection: {:?}", err)).emit());
+    latency::finish(Component::Named("Replay"), stats_out.as_deref());
+}
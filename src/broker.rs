@@ -0,0 +1,584 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use crate::market_data::{Applied, Book};
+use crate::message::{decode, decode_with, BookNotice, BrokerControl, BrokerStatus, CancelOrder, EndOfSession, ExchangeRequest, Execution, ExecutionReport, FinalReport, LimitOrder,
+    MarketData, OrderControl, OrderStatus, OrderType, Side, SnapshotRequest, TimeInForce, UserRequest};
+use crate::config::Config;
+use crate::journal::{self, Event};
+use crate::logging::{self, Component};
+use crate::metrics;
+use crate::order::{control_reply, now_millis, WorkingOrder};
+use crate::portfolio::Portfolio;
+use crate::risk::{self, Exposure};
+use crate::session;
+use crate::transport::{Result, Subscription, Transport};
+use crate::{iterate_stock_list, Order, PurchaseDetails, Stock, ANSI_BOLD_GREEN, ANSI_BOLD_RED, ANSI_RESET};
+
+fn send_report(transport: &dyn Transport, reply_to: &str, report: &ExecutionReport) -> Result<()>{
+    let report_json = serde_json::to_string(report).expect("Failed to serialize");
+    transport.publish(reply_to, report_json.as_bytes())
+}
+
+fn send_control(transport: &dyn Transport, registry: &str, control: &BrokerControl) -> Result<()>{
+    let control_json = serde_json::to_string(control).expect("Failed to serialize");
+    transport.publish(registry, control_json.as_bytes())
+}
+
+// Tell the users' routers this broker's terms and how many orders it is working
+fn send_status(transport: &dyn Transport, config: &Config, broker_no: i8, outstanding: usize, active: bool) -> Result<()>{
+    let status = BrokerStatus{broker_no, terms:config.terms(broker_no), outstanding, active};
+    let status_json = serde_json::to_string(&status).expect("Failed to serialize");
+    transport.broadcast(&config.queues.broker_status, status_json.as_bytes())
+}
+
+// Send an order to the exchange's book, a triggered stop goes as a market or limit order
+fn submit_order(transport: &dyn Transport, queue: &str, working: &WorkingOrder) -> Result<()>{
+    let order = LimitOrder{order_id:working.order.order_id.clone(), broker_no:working.broker_no, user_id:working.order.client_id,
+        stock_name:working.order.stock_name.clone(), side:working.order.side, price:working.order.bid_price, num_stock:working.order.num_stock,
+        order_type:working.order.order_type.on_trigger(), time_in_force:working.order.time_in_force};
+    let order_json = serde_json::to_string(&order).expect("Failed to serialize");
+    transport.publish(queue, order_json.as_bytes())
+}
+
+// Accept an order and send it on to the exchange, stops wait here until they trigger
+fn forward_order(transport: &dyn Transport, queue: &str, working: &mut WorkingOrder) -> Result<()>{
+    if !working.armed{submit_order(transport, queue, working)?;}
+    let report = working.accept();
+    send_report(transport, &working.order.reply_to, &report)
+}
+
+// Send the stops a new price reached on to the exchange
+fn trigger_stops(transport: &dyn Transport, queue: &str, broker_no: i8, stock_name: &str, price: f64,
+    pending_orders: &mut HashMap<String, WorkingOrder>) -> Result<()>{
+    let mut order_ids: Vec<String> = pending_orders.values().filter(|w| w.armed && w.order.stock_name == stock_name).map(|w| w.order.order_id.clone()).collect();
+    order_ids.sort();
+    for order_id in order_ids{
+        let working = pending_orders.get_mut(&order_id).unwrap();
+        let stop_price = working.order.stop_price.unwrap_or_default();
+        let reached = match working.order.side{Side::Buy => price >= stop_price, Side::Sell => price <= stop_price};
+        if !reached{continue;}
+        working.armed = false;
+        logging::info(Component::Broker(broker_no), format!("{} order {} for User {}'s [{}] triggered at {} (stop {})",
+            working.order.order_type, order_id, working.order.client_id, stock_name, price.round(), stop_price.round()))
+            .field("order_id", &order_id).user(working.order.client_id).symbol(stock_name).price(price).field("stop_price", stop_price).emit();
+        submit_order(transport, queue, working)?;
+    }
+    Ok(())
+}
+
+// Apply a market data message to the broker's book, a gap is logged and counted
+fn apply_market_data(book: &mut Book, broker_no: i8, body: &[u8]) -> Option<(MarketData, Applied)>{
+    let message: MarketData = decode(Component::Broker(broker_no), body)?;
+    let applied = book.apply(&message);
+    match (&message, applied){
+        (_, Applied::Gap{last:None, got}) => {
+            logging::debug(Component::Broker(broker_no), format!("market data at update {} before any snapshot", got)).field("seq", got).emit();
+        }
+        (_, Applied::Gap{last:Some(last), got}) => {
+            logging::warn(Component::Broker(broker_no), format!("market data gap, update {} came after {}", got, last))
+                .field("last_seq", last).field("seq", got).emit();
+            metrics::inc(&metrics::MARKET_DATA_GAPS, &[("component", &format!("broker {}", broker_no))]);
+        }
+        (MarketData::Snapshot{seq, stocks, ..}, Applied::Applied) => {
+            logging::debug(Component::Broker(broker_no), format!("market data snapshot at update {}", seq)).field("seq", seq).emit();
+            for stock in stocks.iter(){
+                metrics::set(&metrics::PRICE, &[("symbol", &stock.name)], stock.value);
+            }
+        }
+        _ => {}
+    }
+    if let (MarketData::Update{stock_name, price, ..}, Applied::Applied | Applied::Gap{..}) = (&message, applied){
+        metrics::set(&metrics::PRICE, &[("symbol", stock_name)], *price);
+    }
+    Some((message, applied))
+}
+
+fn request_snapshot(transport: &dyn Transport, config: &Config, broker_no: i8, last_seq: Option<u64>) -> Result<()>{
+    let request_json = serde_json::to_string(&SnapshotRequest{broker_no, last_seq}).expect("Failed to serialize");
+    transport.publish(&config.queues.snapshot_requests, request_json.as_bytes())
+}
+
+// Sell the holdings a new price took past their cut loss, trailing stop or take profit
+fn sell_triggered(transport: &dyn Transport, queue: &str, broker_no: i8, stock_name: &str, price: f64,
+    next_sell_seq: &mut u64, pending_orders: &mut HashMap<String, WorkingOrder>) -> Result<()>{
+    let sold_result = PurchaseDetails::stock_sell_monitoring(stock_name.to_string(), price, broker_no).into_iter().map(|d| (d, price)).collect();
+    send_sells(transport, queue, broker_no, sold_result, next_sell_seq, pending_orders)
+}
+
+fn send_sells(transport: &dyn Transport, queue: &str, broker_no: i8, sold: Vec<(PurchaseDetails, f64)>,
+    next_sell_seq: &mut u64, pending_orders: &mut HashMap<String, WorkingOrder>) -> Result<()>{
+    for (d, price) in sold{
+        // Sell at the trend price, the house bid is quoted there
+        *next_sell_seq+=1;
+        let order = Order{order_id:format!("B{}-{}", broker_no, next_sell_seq), client_id:d.id, stock_name:d.stock_name.clone(),
+            side:Side::Sell, bid_price:price, take_profit:d.take_profit, cut_loss:d.cut_loss, num_stock:d.num_stock,
+            timestamp:now_millis(), reply_to:d.reply_to.clone(), short:false, trailing_stop:None, expire_after_ms:None,
+            order_type:OrderType::Limit, time_in_force:TimeInForce::Gtc, stop_price:None};
+        let mut working = WorkingOrder::new(order, broker_no);
+        working.sold_from = vec![d];
+        forward_order(transport, queue, &mut working)?;
+        pending_orders.insert(working.order.order_id.clone(), working);
+    }
+    Ok(())
+}
+
+// Broker-side checks before an order goes to the exchange
+fn check_order(stock_list: &[Stock], order: &Order) -> std::result::Result<Stock, &'static str>{
+    let stock = iterate_stock_list(stock_list, &order.stock_name).ok_or("stock isn't listed")?;
+    if order.num_stock <= 0{return Err("quantity must be positive");}
+    // a market order's price is the one the user saw
+    if order.bid_price <= 0.0{return Err("price must be positive");}
+    match (order.order_type.is_stop(), order.stop_price){
+        (true, None) => return Err("stop price missing"),
+        (true, Some(stop_price)) if stop_price <= 0.0 => return Err("stop price must be positive"),
+        (false, Some(_)) => return Err("stop price on a market or limit order"),
+        _ => {}
+    }
+    Ok(stock)
+}
+
+// The user's position in the order's stock and gross exposure, over their fills at every
+// broker and the orders still working here. Sells of held shares don't add exposure
+fn exposure(portfolio: &Portfolio, stock_list: &[Stock], pending_orders: &HashMap<String, WorkingOrder>, order: &Order) -> Exposure{
+    let positions = portfolio.snapshot(order.client_id, stock_list).map(|s| s.positions).unwrap_or_default();
+    let mut position = positions.iter().find(|p| p.position.stock_name == order.stock_name).map_or(0, |p| p.position.quantity);
+    let mut gross: f64 = positions.iter().map(|p| p.market_value.abs()).sum();
+    for working in pending_orders.values().filter(|w| w.order.client_id == order.client_id){
+        let leaves = working.leaves_qty();
+        if working.order.side == Side::Buy || working.order.short{gross+=working.order.bid_price * leaves as f64;}
+        if working.order.stock_name == order.stock_name{
+            position+=match working.order.side{Side::Buy => leaves, Side::Sell => -leaves};
+        }
+    }
+    Exposure{position, gross}
+}
+
+// Check a new order, hold what it needs and send it on, or reject it
+fn place_order(transport: &dyn Transport, config: &Config, stock_list: &[Stock], portfolio: &Mutex<Portfolio>,
+    short_positions: &mut HashMap<(i8, String), i128>, pending_orders: &mut HashMap<String, WorkingOrder>, mut working: WorkingOrder) -> Result<()>{
+    let broker_no = working.broker_no;
+    let me = Component::Broker(broker_no);
+    let terms = config.terms(broker_no);
+    let checked = risk::throttle(&config.risk, broker_no, working.order.client_id, now_millis()).and_then(|_| check_order(stock_list, &working.order)).and_then(|stock| {
+        // pre-trade risk limits
+        let exposure = exposure(&portfolio.lock().unwrap(), stock_list, pending_orders, &working.order);
+        risk::check(&config.risk, &working.order, stock.value, &exposure)?;
+        Ok(stock)
+    }).and_then(|stock| {
+        let borrowed: i128 = short_positions.iter().filter(|((id, _), _)| *id == working.order.client_id).map(|(_, qty)| qty).sum();
+        if working.order.short && borrowed + working.order.num_stock > config.borrow_limit.into(){
+            return Err("borrow limit exceeded");
+        }
+        // a plain sell only sells shares held here, some may be in the broker's own sells already
+        if working.order.side == Side::Sell && !working.order.short
+            && PurchaseDetails::held(broker_no, working.order.client_id, &working.order.stock_name) < working.order.num_stock{
+            return Err("not enough shares held");
+        }
+        Ok(stock)
+    }).and_then(|stock| {
+        // hold the cash for a buy until it's filled
+        if working.order.side != Side::Buy{return Ok(stock);}
+        let price = risk::worst_price(&config.risk, &working.order, stock.value);
+        let amount = price * working.order.num_stock as f64 + terms.fee(price, working.order.num_stock);
+        portfolio.lock().unwrap().try_reserve(working.order.client_id, &working.order.order_id, amount).map_err(|available| {
+            logging::info(me, format!("User {} needs {:.2} but only has {:.2} available", working.order.client_id, amount, available))
+                .user(working.order.client_id).field("needed", amount).field("available", available).emit();
+            "insufficient cash"
+        })?;
+        Ok(stock)
+    });
+    match checked {
+        Ok(_) => {
+            let order = &working.order;
+            match (order.side, order.short) {
+                // commit the borrowed shares
+                (Side::Sell, true) => *short_positions.entry((order.client_id, order.stock_name.clone())).or_default()+=order.num_stock,
+                // the shares being sold stop being monitored
+                (Side::Sell, false) => working.sold_from = PurchaseDetails::reduce_order(broker_no, order.client_id, &order.stock_name, order.num_stock),
+                (Side::Buy, _) => {}
+            }
+            forward_order(transport, &config.queues.exchange_orders, &mut working)?;
+            pending_orders.insert(working.order.order_id.clone(), working);
+        }
+        Err(reason) => {
+            logging::info(me, format!("{}unsuccessfully{} shares [{}] for User {}'s order: {}!",
+                ANSI_BOLD_RED, ANSI_RESET, working.order.stock_name, working.order.client_id, reason))
+                .field("order_id", &working.order.order_id).user(working.order.client_id).symbol(&working.order.stock_name).field("reason", reason).emit();
+            metrics::inc(&metrics::REJECTIONS, &[("component", &format!("broker {}", broker_no)), ("reason", reason)]);
+            send_report(transport, &working.order.reply_to.clone(), &working.reject(reason))?;
+        }
+    }
+    Ok(())
+}
+
+// Cancel what's left of a working order: report it, release the cash held for it,
+// give back the borrowed shares it didn't sell and put the held shares it didn't sell
+// back under watch. Returns the order to send in its place when the cancel was for a replace
+fn cancel_order(transport: &dyn Transport, portfolio: &Mutex<Portfolio>, short_positions: &mut HashMap<(i8, String), i128>,
+    mut working: WorkingOrder, reason: &str) -> Result<Option<Order>>{
+    let replacement = working.replacement.take();
+    let order = &working.order;
+    let outcome = match &replacement{
+        Some(new) => format!("replaced by {}", new.order_id),
+        None => format!("cancelled with {} units left: {}", working.leaves_qty(), reason),
+    };
+    logging::info(Component::Broker(working.broker_no), format!("order {} for User {}'s [{}] {}", order.order_id, order.client_id, order.stock_name, outcome))
+        .field("order_id", &order.order_id).user(order.client_id).symbol(&order.stock_name).field("leaves", working.leaves_qty()).field("reason", reason)
+        .field("replaced_by", replacement.as_ref().map(|new| new.order_id.clone())).emit();
+    if order.short{
+        if let Some(borrowed) = short_positions.get_mut(&(order.client_id, order.stock_name.clone())){*borrowed-=working.leaves_qty();}
+        short_positions.retain(|_, borrowed| *borrowed > 0);
+    }
+    PurchaseDetails::restore(&working.sold_from, working.leaves_qty());
+    portfolio.lock().unwrap().release(order.client_id, &order.order_id, None);
+    let report = match &replacement{
+        Some(new) => working.replace(&new.order_id),
+        None => working.cancel(),
+    };
+    send_report(transport, &working.order.reply_to.clone(), &report)?;
+    Ok(replacement)
+}
+
+// A replacement that won't be placed, so the user stops waiting on it
+fn reject_replacement(transport: &dyn Transport, broker_no: i8, replacement: Order, reason: &str) -> Result<()>{
+    let mut working = WorkingOrder::new(replacement, broker_no);
+    logging::info(Component::Broker(broker_no), format!("replacement {} for User {}'s [{}] rejected: {}",
+        working.order.order_id, working.order.client_id, working.order.stock_name, reason))
+        .field("order_id", &working.order.order_id).user(working.order.client_id).symbol(&working.order.stock_name).field("reason", reason).emit();
+    send_report(transport, &working.order.reply_to.clone(), &working.reject(reason))
+}
+
+// A user's cancel, replace or amend. Stops still held here go at once, orders on the
+// exchange's book wait for its answer. Returns a replacement to place now
+fn handle_control(transport: &dyn Transport, queue: &str, broker_no: i8, portfolio: &Mutex<Portfolio>,
+    short_positions: &mut HashMap<(i8, String), i128>, pending_orders: &mut HashMap<String, WorkingOrder>, control: OrderControl) -> Result<Option<Order>>{
+    let me = Component::Broker(broker_no);
+    let (order_id, client_id, stock_name, side, reply_to, replacement) = match control{
+        OrderControl::AmendLevels{request_id, client_id, stock_name, take_profit, cut_loss, reply_to} => {
+            let (status, outcome) = match PurchaseDetails::amend_levels(broker_no, client_id, &stock_name, take_profit, cut_loss){
+                Ok(()) => (OrderStatus::LevelsAmended, "done".to_string()),
+                Err(reason) => (OrderStatus::AmendRejected{reason:reason.clone()}, format!("refused, {}", reason)),
+            };
+            logging::info(me, format!("amend {} of User {}'s [{}] levels: {}", request_id, client_id, stock_name, outcome))
+                .field("order_id", &request_id).user(client_id).symbol(&stock_name).field("take_profit", take_profit).field("cut_loss", cut_loss).emit();
+            return send_report(transport, &reply_to, &control_reply(&request_id, client_id, broker_no, &stock_name, Side::Buy, status)).map(|_| None);
+        }
+        OrderControl::Cancel{order_id, client_id, stock_name, side, reply_to} => (order_id, client_id, stock_name, side, reply_to, None),
+        OrderControl::Replace{order_id, order} => (order_id, order.client_id, order.stock_name.clone(), order.side, order.reply_to.clone(), Some(order)),
+    };
+    let refused = match pending_orders.get(&order_id){
+        None => Some("unknown order, it may have completed"),
+        Some(working) if working.order.client_id != client_id => Some("not the user's order"),
+        Some(working) if working.cancel_requested => Some("a cancel is already pending"),
+        Some(working) if replacement.as_ref().is_some_and(|new| new.stock_name != working.order.stock_name || new.side != working.order.side || new.short != working.order.short)
+            => Some("the replacement must be for the same stock and side"),
+        Some(_) if replacement.as_ref().is_some_and(|new| pending_orders.contains_key(&new.order_id)) => Some("the replacement's order id is in use"),
+        Some(_) => None,
+    };
+    if let Some(reason) = refused{
+        logging::info(me, format!("refused to cancel order {} for User {}: {}", order_id, client_id, reason))
+            .field("order_id", &order_id).user(client_id).symbol(&stock_name).field("reason", reason).emit();
+        let report = match pending_orders.get(&order_id){
+            Some(working) if working.order.client_id == client_id => working.cancel_reject(reason),
+            _ => control_reply(&order_id, client_id, broker_no, &stock_name, side, OrderStatus::CancelRejected{reason:reason.to_string()}),
+        };
+        send_report(transport, &reply_to, &report)?;
+        if let Some(replacement) = replacement{
+            // an id already in use belongs to a working order the report would end
+            if !pending_orders.contains_key(&replacement.order_id){reject_replacement(transport, broker_no, replacement, reason)?;}
+        }
+        return Ok(None);
+    }
+    let working = pending_orders.get_mut(&order_id).unwrap();
+    working.replacement = replacement;
+    if working.armed{
+        // never reached the exchange
+        let working = pending_orders.remove(&order_id).unwrap();
+        return cancel_order(transport, portfolio, short_positions, working, "cancelled on request");
+    }
+    working.cancel_requested = true;
+    let request = ExchangeRequest::Cancel(CancelOrder{order_id, broker_no, user_id:client_id, stock_name});
+    let request_json = serde_json::to_string(&request).expect("Failed to serialize");
+    transport.publish(queue, request_json.as_bytes())?;
+    Ok(None)
+}
+
+// Print each user's cash, positions and P&L marked to the stock list
+pub fn print_portfolios(portfolio: &Portfolio, stock_list: &[Stock]){
+    for user_id in portfolio.user_ids(){
+        let Some(snapshot) = portfolio.snapshot(user_id, stock_list) else {continue};
+        let line = format!("Cash: {:.2} | Equity: {:.2} | Realised P&L: {:.2} | Unrealised P&L: {:.2} | Fees: {:.2}",
+            snapshot.cash, snapshot.equity, snapshot.realised_pnl, snapshot.unrealised_pnl, snapshot.fees);
+        logging::info(Component::User(user_id), line.clone()).display(format!("User {}: {}", user_id, line))
+            .user(user_id).field("cash", snapshot.cash).field("equity", snapshot.equity).field("realised_pnl", snapshot.realised_pnl)
+            .field("unrealised_pnl", snapshot.unrealised_pnl).field("fees", snapshot.fees).emit();
+        for p in snapshot.positions.iter().filter(|p| p.position.quantity != 0){
+            let line = format!("[{}] {} units - Avg Cost: {:.2} | Last: {:.2} | Unrealised P&L: {:.2}",
+                p.position.stock_name, p.position.quantity, p.position.avg_cost, p.last_price, p.unrealised_pnl);
+            logging::info(Component::User(user_id), line.clone()).display(format!("    {}", line))
+                .user(user_id).symbol(&p.position.stock_name).field("quantity", p.position.quantity).field("avg_cost", p.position.avg_cost)
+                .price(p.last_price).field("unrealised_pnl", p.unrealised_pnl).emit();
+        }
+    }
+}
+
+// Every user's snapshot as metrics gauges, so a portfolio can be followed during the run
+fn publish_portfolios(portfolio: &Portfolio, stock_list: &[Stock], component: &str){
+    for snapshot in portfolio.user_ids().into_iter().filter_map(|user_id| portfolio.snapshot(user_id, stock_list)){
+        let user = snapshot.user_id.to_string();
+        let labels = [("component", component), ("user", user.as_str())];
+        metrics::set(&metrics::CASH, &labels, snapshot.cash);
+        metrics::set(&metrics::RESERVED_CASH, &labels, snapshot.reserved);
+        metrics::set(&metrics::EQUITY, &labels, snapshot.equity);
+        metrics::set(&metrics::REALISED_PNL, &labels, snapshot.realised_pnl);
+        metrics::set(&metrics::UNREALISED_PNL, &labels, snapshot.unrealised_pnl);
+    }
+}
+
+// Why the session is over, if it is: the exchange closed it or this process was interrupted
+fn session_over(session_end: &dyn Subscription, broker_no: i8) -> Result<Option<String>>{
+    while let Some(body) = session_end.recv_timeout(Duration::ZERO)?{
+        if let Some(end) = decode::<EndOfSession>(Component::Broker(broker_no), &body){return Ok(Some(end.reason));}
+    }
+    Ok(session::stop_requested().then(|| "interrupted".to_string()))
+}
+
+// Broker's execution, returns once the exchange ended the session or the process was interrupted.
+// `portfolio` is the broker's view of every user's account, see `Portfolio` for what a shared one
+// changes. Each user's snapshot is published as metrics once a round.
+pub fn run(transport: &dyn Transport, config: &Config, broker_no: i8, portfolio: &Arc<Mutex<Portfolio>>) -> Result<()> {
+    let queues = &config.queues;
+    let timing = &config.timing;
+    let component = format!("broker {}", broker_no); // metrics label
+    /* ---------------------- Broker Receiver --------------------- */
+    // users' orders routed to this broker
+    let usr_order_list = transport.consume(&queues.orders_for(broker_no))?;
+    // market data snapshots & heartbeats
+    let exch_brk_stock_list = transport.subscribe(&queues.stock_info)?;
+    // market data updates, the stock trends
+    let exch_brk_stock_trend = transport.subscribe(&queues.stock_trending)?;
+    // fills from the exchange's order book
+    let exch_brk_executions = transport.subscribe(&queues.executions)?;
+    // orders the book cancelled & trading day closes
+    let exch_brk_notices = transport.subscribe(&queues.order_notices)?;
+    // the exchange's end of session
+    let session_end = transport.subscribe(&queues.session)?;
+    // only registered brokers get their orders matched
+    transport.declare_queue(&queues.registry)?;
+    send_control(transport, &queues.registry, &BrokerControl::Register{broker_no})?;
+    send_status(transport, config, broker_no, 0, true)?;
+    // the exchange answers with a snapshot at the start of its next round
+    transport.declare_queue(&queues.snapshot_requests)?;
+    request_snapshot(transport, config, broker_no, None)?;
+
+    let me = Component::Broker(broker_no);
+    logging::info(me, "Waiting for messages. Press Ctrl-C to exit.").emit();
+
+    // the stock list as the exchange's market data left it
+    let mut book = Book::default();
+    // users' orders still working on the exchange, kept for their cut loss & take profit
+    let mut pending_orders: HashMap<String, WorkingOrder> = HashMap::new();
+    // the broker's own sell orders
+    let mut next_sell_seq: u64 = 0;
+    // shares each user borrowed for short selling, by (user, stock)
+    let mut short_positions: HashMap<(i8, String), i128> = HashMap::new();
+    // the book's notices for orders whose fills haven't all come in yet
+    let mut notices: Vec<BookNotice> = Vec::new();
+    let reason = loop {
+        // Get the round's snapshot or heartbeat
+        let timeout_orderlist_duration = Duration::from_millis(timing.broker_stock_list_timeout_ms);
+        match exch_brk_stock_list.recv_timeout(timeout_orderlist_duration)? {
+            Some(body) => {
+                let Some(message) = decode::<MarketData>(me, &body) else {continue};
+                // updates still queued come after a snapshot but before a heartbeat
+                if let MarketData::Snapshot{..} = message{apply_market_data(&mut book, broker_no, &body);}
+                while let Some(update) = exch_brk_stock_trend.recv_timeout(Duration::ZERO)?{
+                    if let Some((MarketData::Update{stock_name, price, ..}, Applied::Applied | Applied::Gap{..})) = apply_market_data(&mut book, broker_no, &update){
+                        sell_triggered(transport, &queues.exchange_orders, broker_no, &stock_name, price, &mut next_sell_seq, &mut pending_orders)?;
+                        trigger_stops(transport, &queues.exchange_orders, broker_no, &stock_name, price, &mut pending_orders)?;
+                    }
+                }
+                if let MarketData::Heartbeat{..} = message{apply_market_data(&mut book, broker_no, &body);}
+                if book.needs_snapshot(){request_snapshot(transport, config, broker_no, book.seq())?;}
+                // expired holdings go even if their price didn't move
+                let sold = PurchaseDetails::book_sell_monitoring(book.stocks(), broker_no);
+                send_sells(transport, &queues.exchange_orders, broker_no, sold, &mut next_sell_seq, &mut pending_orders)?;
+                for stock in book.stocks().to_vec(){
+                    trigger_stops(transport, &queues.exchange_orders, broker_no, &stock.name, stock.value, &mut pending_orders)?;
+                }
+            }
+            None => {
+                logging::debug(me, "Order List: Timeout reached. No message received.").emit();
+            }
+        }
+
+        send_status(transport, config, broker_no, pending_orders.len(), true)?;
+        if let Some(reason) = session_over(&*session_end, broker_no)?{break reason;}
+
+        // User request -> buy, or a cancel/amend of an earlier one
+        let timeout_purchase_duration = Duration::from_millis(timing.broker_order_timeout_ms);
+        while let Some(body) = usr_order_list.recv_timeout(timeout_purchase_duration)? {
+            let Some(request) = decode_with(me, &body, UserRequest::from_slice) else {continue};
+            let user_list = match request{
+                UserRequest::Order(order) => order,
+                UserRequest::Control(control) => {
+                    journal::record(&format!("broker {}", broker_no), Event::OrderControlReceived{broker_no, control:control.clone()});
+                    if let Some(replacement) = handle_control(transport, &queues.exchange_orders, broker_no, portfolio, &mut short_positions, &mut pending_orders, control)?{
+                        place_order(transport, config, book.stocks(), portfolio, &mut short_positions, &mut pending_orders, WorkingOrder::new(replacement, broker_no))?;
+                    }
+                    continue;
+                }
+            };
+            logging::info(me, format!("had received order {} from User {}", user_list.order_id, user_list.client_id))
+                .field("order_id", &user_list.order_id).user(user_list.client_id).symbol(&user_list.stock_name).emit();
+            journal::record(&format!("broker {}", broker_no), Event::OrderReceived{broker_no, order:user_list.clone()});
+            metrics::inc(&metrics::ORDERS_RECEIVED, &[("component", &component)]);
+            place_order(transport, config, book.stocks(), portfolio, &mut short_positions, &mut pending_orders, WorkingOrder::new(user_list, broker_no))?;
+        }
+        logging::debug(me, "Purchasing: Timeout reached. No message received.").emit();
+        send_status(transport, config, broker_no, pending_orders.len(), true)?;
+
+        // fills -> purchase records
+        let timeout_execution_duration = Duration::from_millis(timing.broker_execution_timeout_ms);
+        while let Some(body) = exch_brk_executions.recv_timeout(timeout_execution_duration)? {
+            let Some(exec) = decode::<Execution>(me, &body) else {continue};
+            let fee = config.terms(exec.broker_no).fee(exec.price, exec.num_stock);
+            {
+                let mut portfolio = portfolio.lock().unwrap();
+                if portfolio.books(&exec, broker_no){portfolio.apply_fill(&exec, fee);}
+            }
+            // fills of other brokers' orders only count towards the portfolios
+            if exec.broker_no != broker_no{continue;}
+            let Some(working) = pending_orders.get_mut(&exec.order_id) else {continue};
+            metrics::inc(&metrics::FILLS, &[("component", &component)]);
+            let user_list = &working.order;
+            match exec.side {
+                Side::Buy => {
+                    logging::info(me, format!("had {}successfully purchased [{}] stock {} with {} units for (User {}) - At Price: {} | {}Cut Loss: {}{} | {}Take Profit: {} {}",
+                        ANSI_BOLD_GREEN, exec.stock_name,ANSI_RESET, exec.num_stock, exec.user_id, exec.price.round(),ANSI_BOLD_RED,user_list.cut_loss.round(),
+                        ANSI_RESET,ANSI_BOLD_GREEN,user_list.take_profit.round(),ANSI_RESET))
+                        .field("order_id", &exec.order_id).user(exec.user_id).symbol(&exec.stock_name).field("quantity", exec.num_stock).price(exec.price)
+                        .field("cut_loss", user_list.cut_loss).field("take_profit", user_list.take_profit)
+                        .field("trailing_stop", user_list.trailing_stop).field("expire_after_ms", user_list.expire_after_ms).emit();
+                    // Cover the user's short position first, the rest is a new holding
+                    let mut bought = exec.num_stock;
+                    if let Some(borrowed) = short_positions.get_mut(&(exec.user_id, exec.stock_name.clone())){
+                        let covered = bought.min(*borrowed);
+                        *borrowed-=covered;
+                        bought-=covered;
+                    }
+                    short_positions.retain(|_, borrowed| *borrowed > 0);
+                    // Save purchase records
+                    if bought > 0{
+                        PurchaseDetails::add_order(PurchaseDetails::for_fill(broker_no, user_list, bought, exec.price));
+                    }
+                }
+                Side::Sell => {
+                    logging::info(me, format!("sold {} units of User {}'s [{}]{} - At Price: {}",
+                        exec.num_stock, exec.user_id, exec.stock_name, if user_list.short{" short"}else{""}, exec.price.round()))
+                        .field("order_id", &exec.order_id).user(exec.user_id).symbol(&exec.stock_name).field("quantity", exec.num_stock).price(exec.price)
+                        .field("short", user_list.short).emit();
+                }
+            }
+            let report = working.fill(exec.num_stock, exec.price, fee);
+            send_report(transport, &working.order.reply_to, &report)?;
+            let mut portfolio = portfolio.lock().unwrap();
+            if working.order.side == Side::Buy{
+                // what the fill cost is spent, the rest stays held until the order is done
+                portfolio.release(exec.user_id, &exec.order_id, Some(exec.price * exec.num_stock as f64 + fee));
+            }
+            if working.is_done(){
+                // a cancel or replace that came too late
+                if working.cancel_requested{
+                    send_report(transport, &working.order.reply_to, &working.cancel_reject("the order filled"))?;
+                    if let Some(replacement) = working.replacement.take(){reject_replacement(transport, broker_no, replacement, "the order filled")?;}
+                }
+                portfolio.release(exec.user_id, &exec.order_id, None);
+                pending_orders.remove(&exec.order_id);
+            }
+        }
+
+        // orders the book cancelled, held back until the fills before the cancel have come in.
+        // A cancel for a replace sends the replacement on
+        while let Some(body) = exch_brk_notices.recv_timeout(Duration::ZERO)?{
+            notices.extend(decode::<BookNotice>(me, &body));
+        }
+        let mut waiting = Vec::new();
+        for notice in notices.drain(..){
+            match &notice{
+                BookNotice::Cancelled{order_id, broker_no:owner, filled_qty, reason, ..} if *owner == broker_no => {
+                    let Some(working) = pending_orders.get(order_id) else {continue};
+                    if working.cum_qty < *filled_qty{
+                        waiting.push(notice.clone());
+                        continue;
+                    }
+                    let working = pending_orders.remove(order_id).unwrap();
+                    if let Some(replacement) = cancel_order(transport, portfolio, &mut short_positions, working, reason)?{
+                        place_order(transport, config, book.stocks(), portfolio, &mut short_positions, &mut pending_orders, WorkingOrder::new(replacement, broker_no))?;
+                    }
+                }
+                BookNotice::CancelRejected{order_id, broker_no:owner, reason} if *owner == broker_no => {
+                    let Some(working) = pending_orders.get_mut(order_id) else {continue};
+                    working.cancel_requested = false;
+                    send_report(transport, &working.order.reply_to, &working.cancel_reject(reason))?;
+                    if let Some(replacement) = working.replacement.take(){
+                        logging::info(me, format!("order {} wasn't replaced by {}: {}", order_id, replacement.order_id, reason))
+                            .field("order_id", order_id).user(replacement.client_id).field("replaced_by", &replacement.order_id).field("reason", reason).emit();
+                        reject_replacement(transport, broker_no, replacement, reason)?;
+                    }
+                }
+                BookNotice::DayClosed{day} => {
+                    // stops that never triggered expire with the day too
+                    let expired: Vec<String> = pending_orders.values().filter(|w| w.armed && w.order.time_in_force == TimeInForce::Day)
+                        .map(|w| w.order.order_id.clone()).collect();
+                    for order_id in expired{
+                        let working = pending_orders.remove(&order_id).unwrap();
+                        cancel_order(transport, portfolio, &mut short_positions, working, &format!("day {} closed before the stop triggered", day))?;
+                    }
+                }
+                BookNotice::Cancelled{..} | BookNotice::CancelRejected{..} => {}
+            }
+        }
+        notices = waiting;
+        publish_portfolios(&portfolio.lock().unwrap(), book.stocks(), &component);
+
+        // the exchange publishes its last fills before ending the session
+        if let Some(reason) = session_over(&*session_end, broker_no)?{break reason;}
+
+        // monitoring -> sell action
+        logging::debug(me, "Monitoring the stocks...").emit();
+        let timeout_selling_monitor_duration = Duration::from_millis(timing.broker_trend_timeout_ms);
+        while let Some(body) = exch_brk_stock_trend.recv_timeout(timeout_selling_monitor_duration)? {
+            if let Some((MarketData::Update{stock_name, price, ..}, Applied::Applied | Applied::Gap{..})) = apply_market_data(&mut book, broker_no, &body){
+                sell_triggered(transport, &queues.exchange_orders, broker_no, &stock_name, price, &mut next_sell_seq, &mut pending_orders)?;
+                trigger_stops(transport, &queues.exchange_orders, broker_no, &stock_name, price, &mut pending_orders)?;
+            }
+        }
+        logging::debug(me, "Monitor: Timeout reached. No message received.").emit();
+        if let Some(reason) = session_over(&*session_end, broker_no)?{break reason;}
+    };
+
+    // Orderly shutdown: nothing more will fill, so cancel what's still working
+    // and flush the holdings under watch, then report and deregister
+    logging::info(me, format!("Session ended ({}), shutting down", reason)).field("reason", &reason).emit();
+    let mut cancelled = Vec::new();
+    for (order_id, mut working) in pending_orders.drain(){
+        // still held, so flushed with the rest below
+        PurchaseDetails::restore(&working.sold_from, working.leaves_qty());
+        send_report(transport, &working.order.reply_to.clone(), &working.cancel())?;
+        if let Some(replacement) = working.replacement.take(){reject_replacement(transport, broker_no, replacement, "the session ended")?;}
+        portfolio.lock().unwrap().release(working.order.client_id, &order_id, None);
+        cancelled.push(order_id);
+    }
+    cancelled.sort();
+    let open_positions = PurchaseDetails::flush(broker_no);
+    logging::info(me, format!("cancelled {} working orders, flushed {} open positions", cancelled.len(), open_positions.len()))
+        .field("cancelled", cancelled.len()).field("open_positions", open_positions.len()).emit();
+    let portfolio = portfolio.lock().unwrap();
+    print_portfolios(&portfolio, book.stocks());
+    let portfolios = portfolio.user_ids().into_iter().filter_map(|user_id| portfolio.snapshot(user_id, book.stocks())).collect();
+    let report = FinalReport{broker_no, cancelled, open_positions, portfolios};
+    let report_json = serde_json::to_string(&report).expect("Failed to serialize");
+    transport.broadcast(&queues.final_reports, report_json.as_bytes())?;
+    send_status(transport, config, broker_no, 0, false)?;
+    send_control(transport, &queues.registry, &BrokerControl::Deregister{broker_no})
+}
@@ -0,0 +1,256 @@
+// Price history per symbol and its OHLCV candles. The exchange records every trade
+// and list price change as a tick, and each tick updates one candle series per
+// configured interval, by tick count ("10t") or by time ("1s", "1m").
+use std::{collections::BTreeMap, fmt, fs, io, sync::Mutex};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash,PartialOrd,Ord,Serialize,Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Interval{
+    Ticks(u32), // a candle closes after this many ticks
+    Millis(i64), // candles start on multiples of this since the epoch
+}
+
+impl Interval{
+    // "<n>t" or "<n>ticks", "<n>ms", "<n>s", "<n>m" or "<n>h"
+    pub fn parse(spec: &str) -> Result<Interval, String>{
+        let spec = spec.trim();
+        let split = spec.find(|c: char| !c.is_ascii_digit()).unwrap_or(spec.len());
+        let (count, unit) = spec.split_at(split);
+        let count: i64 = count.parse().map_err(|_| format!("interval {:?} must start with a number", spec))?;
+        if count <= 0{return Err(format!("interval {:?} must be positive", spec));}
+        let millis = match unit{
+            "t" | "tick" | "ticks" => return u32::try_from(count).map(Interval::Ticks).map_err(|_| format!("interval {:?} is too long", spec)),
+            "ms" => 1,
+            "s" => 1000,
+            "m" => 60_000,
+            "h" => 3_600_000,
+            other => return Err(format!("unknown interval unit {:?} in {:?}, expected t, ms, s, m or h", other, spec)),
+        };
+        count.checked_mul(millis).map(Interval::Millis).ok_or_else(|| format!("interval {:?} is too long", spec))
+    }
+}
+
+impl fmt::Display for Interval{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match *self{
+            Interval::Ticks(n) => write!(f, "{}t", n),
+            Interval::Millis(ms) if ms % 3_600_000 == 0 => write!(f, "{}h", ms / 3_600_000),
+            Interval::Millis(ms) if ms % 60_000 == 0 => write!(f, "{}m", ms / 60_000),
+            Interval::Millis(ms) if ms % 1000 == 0 => write!(f, "{}s", ms / 1000),
+            Interval::Millis(ms) => write!(f, "{}ms", ms),
+        }
+    }
+}
+
+impl TryFrom<String> for Interval{
+    type Error = String;
+    fn try_from(spec: String) -> Result<Interval, String>{
+        Interval::parse(&spec)
+    }
+}
+
+impl From<Interval> for String{
+    fn from(interval: Interval) -> String{
+        interval.to_string()
+    }
+}
+
+// `[candles]` in the config file
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CandleConfig{
+    pub intervals: Vec<Interval>,
+    pub max_history: usize, // ticks & candles kept per symbol and series, the oldest go first
+}
+
+impl Default for CandleConfig{
+    fn default() -> Self {
+        CandleConfig{intervals:vec![Interval::Ticks(10), Interval::Millis(1000), Interval::Millis(60_000)], max_history:100_000}
+    }
+}
+
+impl CandleConfig{
+    pub fn validate(&self) -> Result<(), String>{
+        if self.intervals.is_empty(){return Err("at least one interval is needed".to_string());}
+        for (i, interval) in self.intervals.iter().enumerate(){
+            if self.intervals[..i].contains(interval){return Err(format!("interval {} is listed twice", interval));}
+        }
+        if self.max_history == 0{return Err("max_history must be positive".to_string());}
+        Ok(())
+    }
+}
+
+// One price observation, volume is 0 when only the list price moved
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+pub struct Tick{
+    pub timestamp: i64, // ms since epoch
+    pub price: f64,
+    pub volume: i128,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+pub struct Candle{
+    pub start: i64, // bucket start for time intervals, first tick for tick intervals
+    pub end: i64, // last tick
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: i128,
+    pub ticks: u32,
+}
+
+impl Candle{
+    fn open(start: i64, tick: &Tick) -> Candle{
+        Candle{start, end:tick.timestamp, open:tick.price, high:tick.price, low:tick.price, close:tick.price, volume:tick.volume, ticks:1}
+    }
+
+    fn update(&mut self, tick: &Tick){
+        self.end = tick.timestamp;
+        self.high = self.high.max(tick.price);
+        self.low = self.low.min(tick.price);
+        self.close = tick.price;
+        self.volume+=tick.volume;
+        self.ticks+=1;
+    }
+}
+
+// Add a tick to a series, opening a new candle when it falls outside the last one
+fn aggregate(candles: &mut Vec<Candle>, interval: Interval, tick: &Tick){
+    let start = match interval{
+        Interval::Ticks(_) => tick.timestamp,
+        Interval::Millis(ms) => tick.timestamp - tick.timestamp.rem_euclid(ms),
+    };
+    let same = match (candles.last(), interval){
+        (Some(last), Interval::Ticks(n)) => last.ticks < n,
+        (Some(last), Interval::Millis(_)) => last.start == start,
+        (None, _) => false,
+    };
+    match candles.last_mut(){
+        Some(last) if same => last.update(tick),
+        _ => candles.push(Candle::open(start, tick)),
+    }
+}
+
+fn trim<T>(items: &mut Vec<T>, limit: usize){
+    if items.len() > limit{items.drain(..items.len() - limit);}
+}
+
+#[derive(Default)]
+struct SymbolHistory{
+    ticks: Vec<Tick>,
+    candles: BTreeMap<Interval, Vec<Candle>>,
+}
+
+struct History{
+    config: CandleConfig,
+    symbols: BTreeMap<String, SymbolHistory>,
+}
+
+lazy_static! {
+    static ref HISTORY: Mutex<History> = Mutex::new(History{config:CandleConfig::default(), symbols:BTreeMap::new()});
+}
+
+// Intervals for the series opened from now on, the exchange sets them from its config
+pub fn configure(config: &CandleConfig){
+    HISTORY.lock().unwrap().config = config.clone();
+}
+
+pub fn record(symbol: &str, timestamp: i64, price: f64, volume: i128){
+    let mut history = HISTORY.lock().unwrap();
+    let History{config, symbols} = &mut *history;
+    let tick = Tick{timestamp, price, volume};
+    let symbol = symbols.entry(symbol.to_string()).or_default();
+    symbol.ticks.push(tick);
+    trim(&mut symbol.ticks, config.max_history);
+    for interval in config.intervals.iter(){
+        let candles = symbol.candles.entry(*interval).or_default();
+        aggregate(candles, *interval, &tick);
+        trim(candles, config.max_history);
+    }
+}
+
+pub fn symbols() -> Vec<String>{
+    HISTORY.lock().unwrap().symbols.keys().cloned().collect()
+}
+
+pub fn intervals() -> Vec<Interval>{
+    HISTORY.lock().unwrap().config.intervals.clone()
+}
+
+pub fn ticks(symbol: &str) -> Vec<Tick>{
+    HISTORY.lock().unwrap().symbols.get(symbol).map(|s| s.ticks.clone()).unwrap_or_default()
+}
+
+// Oldest first, the last candle may still be open
+pub fn candles(symbol: &str, interval: Interval) -> Vec<Candle>{
+    HISTORY.lock().unwrap().symbols.get(symbol).and_then(|s| s.candles.get(&interval)).cloned().unwrap_or_default()
+}
+
+pub fn latest(symbol: &str, interval: Interval) -> Option<Candle>{
+    HISTORY.lock().unwrap().symbols.get(symbol).and_then(|s| s.candles.get(&interval)).and_then(|c| c.last().copied())
+}
+
+// Every symbol's candles for every interval as CSV
+pub fn export_csv(path: &str) -> io::Result<()>{
+    let history = HISTORY.lock().unwrap();
+    let mut csv = String::from("symbol,interval,start,end,open,high,low,close,volume,ticks\n");
+    for (name, symbol) in history.symbols.iter(){
+        for (interval, candles) in symbol.candles.iter(){
+            for c in candles.iter(){
+                csv.push_str(&format!("{},{},{},{},{:.2},{:.2},{:.2},{:.2},{},{}\n", name, interval, c.start, c.end, c.open, c.high, c.low, c.close, c.volume, c.ticks));
+            }
+        }
+    }
+    fs::write(path, csv)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn series(interval: Interval, ticks: &[(i64, f64, i128)]) -> Vec<Candle>{
+        let mut candles = Vec::new();
+        for &(timestamp, price, volume) in ticks{
+            aggregate(&mut candles, interval, &Tick{timestamp, price, volume});
+        }
+        candles
+    }
+
+    #[test]
+    fn time_candles_start_on_interval_multiples(){
+        let candles = series(Interval::Millis(1000), &[(1_200, 10.0, 5), (1_900, 12.0, 0), (1_500, 9.0, 3), (2_000, 11.0, 1)]);
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[0], Candle{start:1_000, end:1_500, open:10.0, high:12.0, low:9.0, close:9.0, volume:8, ticks:3});
+        assert_eq!((candles[1].start, candles[1].open, candles[1].ticks), (2_000, 11.0, 1));
+    }
+
+    #[test]
+    fn tick_candles_close_after_n_ticks(){
+        let ticks: Vec<(i64, f64, i128)> = (0..5).map(|i| (i * 10, 100.0 + i as f64, 1)).collect();
+        let candles = series(Interval::Ticks(2), &ticks);
+        assert_eq!(candles.iter().map(|c| (c.start, c.open, c.close, c.ticks)).collect::<Vec<_>>(),
+            vec![(0, 100.0, 101.0, 2), (20, 102.0, 103.0, 2), (40, 104.0, 104.0, 1)]);
+    }
+
+    #[test]
+    fn intervals_parse_and_print(){
+        for (spec, interval) in [("10t", Interval::Ticks(10)), ("500ms", Interval::Millis(500)), ("1s", Interval::Millis(1000)),
+            ("5m", Interval::Millis(300_000)), ("2h", Interval::Millis(7_200_000))]{
+            assert_eq!(Interval::parse(spec), Ok(interval));
+            assert_eq!(interval.to_string(), spec);
+        }
+        assert!(Interval::parse("0s").is_err());
+        assert!(Interval::parse("3d").is_err());
+        assert!(Interval::parse("s").is_err());
+    }
+
+    #[test]
+    fn trim_drops_the_oldest(){
+        let mut items = vec![1, 2, 3, 4];
+        trim(&mut items, 2);
+        assert_eq!(items, vec![3, 4]);
+    }
+}
@@ -0,0 +1,377 @@
+use std::{env, fs, path::Path};
+use serde::{Deserialize, Serialize};
+use crate::candles::CandleConfig;
+use crate::news::NewsConfig;
+use crate::logging::{self, Level, LogConfig, LogFormat};
+use crate::portfolio::INITIAL_CASH;
+use crate::price_model::{PriceModelConfig, MIN_PRICE};
+use crate::risk::RiskConfig;
+use crate::router::{BrokerTerms, RoutingPolicy};
+use crate::transport::DEFAULT_AMQP_URL;
+use crate::users::UserBehaviour;
+use crate::Stock;
+
+// Everything a scenario can change without editing source. Loaded from a
+// TOML or JSON file (`--config <path>`), missing keys keep their defaults.
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config{
+    pub amqp_url: String,
+    pub brokers: i8, // brokers started by `--in-memory`, numbered 1..=brokers
+    pub num_users: i8,
+    pub initial_cash: f64, // every user's starting cash
+    pub borrow_limit: i64, // shares a user may have sold short at one broker
+    pub routing: RoutingPolicy, // how users pick a broker for each order
+    pub day_rounds: u32, // exchange rounds in a trading day, DAY orders still resting at its close are cancelled
+    pub default_terms: BrokerTerms, // for brokers missing from broker_terms
+    pub broker_terms: Vec<BrokerTerms>,
+    pub users: UserBehaviour,
+    pub risk: RiskConfig, // brokers' pre-trade limits on the users' orders
+    pub price_model: PriceModelConfig,
+    pub queues: QueueConfig,
+    pub timing: TimingConfig,
+    pub log: LogConfig,
+    pub news: NewsConfig, // headlines and how often they break
+    pub candles: CandleConfig, // OHLCV aggregation of each symbol's price history
+    pub stocks: Vec<Stock>, // listed symbols and their initial prices
+}
+
+impl Default for Config{
+    fn default() -> Self {
+        Config{
+            amqp_url: DEFAULT_AMQP_URL.to_string(),
+            brokers: 2,
+            num_users: 10,
+            initial_cash: INITIAL_CASH,
+            borrow_limit: 50,
+            routing: RoutingPolicy::default(),
+            day_rounds: 5,
+            default_terms: BrokerTerms::default(),
+            broker_terms: vec![
+                BrokerTerms{broker_no:1, commission:0.02, markup:0.0},
+                BrokerTerms{broker_no:2, commission:0.0, markup:0.001},
+            ],
+            users: UserBehaviour::default(),
+            risk: RiskConfig::default(),
+            price_model: PriceModelConfig::default(),
+            queues: QueueConfig::default(),
+            timing: TimingConfig::default(),
+            log: LogConfig::default(),
+            news: NewsConfig::default(),
+            candles: CandleConfig::default(),
+            stocks: default_stocks(),
+        }
+    }
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig{
+    pub orders: String, // users' orders, followed by the broker number
+    pub broker_status: String, // fanout of the brokers' terms and load
+    pub exchange_orders: String, // brokers' limit orders to the exchange
+    pub user_reports: String, // brokers' execution reports to the users
+    pub registry: String, // brokers registering with the exchange
+    // fanout exchanges every registered broker subscribes to
+    pub stock_info: String, // market data snapshots & heartbeats
+    pub stock_trending: String, // market data updates
+    pub executions: String,
+    pub order_notices: String, // orders the book cancelled & trading day closes
+    pub session: String, // the exchange's end of session
+    pub final_reports: String, // brokers' reports when they shut down
+    pub snapshot_requests: String, // brokers asking for a market data snapshot
+}
+
+impl Default for QueueConfig{
+    fn default() -> Self {
+        QueueConfig{
+            orders: "linktobr".to_string(),
+            broker_status: "brokerStatus".to_string(),
+            exchange_orders: "submitOrder".to_string(),
+            user_reports: "userReports".to_string(),
+            registry: "brokerRegistry".to_string(),
+            stock_info: "sentStockInfo".to_string(),
+            stock_trending: "sentStockTrending".to_string(),
+            executions: "executions".to_string(),
+            order_notices: "orderNotices".to_string(),
+            session: "session".to_string(),
+            final_reports: "brokerReports".to_string(),
+            snapshot_requests: "snapshotRequest".to_string(),
+        }
+    }
+}
+
+impl QueueConfig{
+    pub fn orders_for(&self, broker_no: i8) -> String{
+        format!("{}{}", self.orders, broker_no)
+    }
+
+    fn names(&self) -> Vec<&str>{
+        vec![&self.orders, &self.broker_status, &self.exchange_orders, &self.user_reports, &self.registry, &self.stock_info, &self.stock_trending, &self.executions,
+            &self.order_notices, &self.session, &self.final_reports, &self.snapshot_requests]
+    }
+}
+
+// Timeouts and tick rates, all in milliseconds
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimingConfig{
+    pub start_delay_ms: u64, // before the exchange and users threads first run
+    pub tick_ms: u64, // rate the exchange and users threads are rescheduled at
+    pub exchange_publish_delay_ms: u64, // before each stock list broadcast
+    pub exchange_order_timeout_ms: u64, // quiet time that ends order matching
+    pub exchange_trend_delay_ms: u64,
+    pub broker_stock_list_timeout_ms: u64,
+    pub broker_order_timeout_ms: u64,
+    pub broker_execution_timeout_ms: u64,
+    pub broker_trend_timeout_ms: u64,
+    pub user_order_delay_ms: u64, // between a user's orders
+    pub user_page_wait_ms: u64, // reading reports while the stock list loads
+    pub user_drain_ms: u64, // collecting fills before the exit-round sells
+    pub user_final_drain_ms: u64,
+    pub shutdown_drain_ms: u64, // exchange waits this long for the brokers' final reports
+}
+
+impl Default for TimingConfig{
+    fn default() -> Self {
+        TimingConfig{
+            start_delay_ms: 50,
+            tick_ms: 1000,
+            exchange_publish_delay_ms: 1000,
+            exchange_order_timeout_ms: 5000,
+            exchange_trend_delay_ms: 200,
+            broker_stock_list_timeout_ms: 5000,
+            broker_order_timeout_ms: 5000,
+            broker_execution_timeout_ms: 1000,
+            broker_trend_timeout_ms: 5000,
+            user_order_delay_ms: 5,
+            user_page_wait_ms: 3000,
+            user_drain_ms: 5000,
+            user_final_drain_ms: 15_000,
+            shutdown_drain_ms: 20_000,
+        }
+    }
+}
+
+impl Config{
+    // Read a `.toml` or `.json` file and validate it
+    pub fn load(path: &str) -> Result<Config, String>{
+        let text = fs::read_to_string(path).map_err(|err| format!("can't read {}: {}", path, err))?;
+        let config: Config = match Path::new(path).extension().and_then(|ext| ext.to_str()){
+            Some("toml") => toml::from_str(&text).map_err(|err| format!("{}: {}", path, err))?,
+            Some("json") => serde_json::from_str(&text).map_err(|err| format!("{}: {}", path, err))?,
+            _ => return Err(format!("{}: expected a .toml or .json file", path)),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    // `--config <path>` on the command line, or the defaults without it
+    // Also applies `--log-format`, `--log-level` & `--log-filter` and sets up the logging
+    pub fn from_args() -> Config{
+        let args: Vec<String> = env::args().collect();
+        let mut config = match args.iter().position(|arg| arg == "--config").map(|i| args.get(i+1)){
+            Some(Some(path)) => Config::load(path).unwrap_or_else(|err| panic!("Invalid --config: {}", err)),
+            Some(None) => panic!("Invalid --config: missing path"),
+            None => Config::default(),
+        };
+        let flag = |name: &str| args.iter().position(|arg| arg == name).and_then(|i| args.get(i+1)).cloned();
+        if let Some(format) = flag("--log-format"){
+            config.log.format = LogFormat::parse(&format).unwrap_or_else(|err| panic!("Invalid --log-format: {}", err));
+        }
+        if let Some(level) = flag("--log-level"){
+            config.log.level = Level::parse(&level).unwrap_or_else(|err| panic!("Invalid --log-level: {}", err));
+        }
+        if let Some(filter) = flag("--log-filter"){
+            config.log.filter = filter;
+        }
+        logging::init(&config.log).unwrap_or_else(|err| panic!("Invalid --log-filter: {}", err));
+        config
+    }
+
+    pub fn terms(&self, broker_no: i8) -> BrokerTerms{
+        self.broker_terms.iter().find(|t| t.broker_no == broker_no).copied()
+            .unwrap_or(BrokerTerms{broker_no, ..self.default_terms})
+    }
+
+    pub fn broker_ids(&self) -> Vec<i8>{
+        (1..=self.brokers).collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String>{
+        if !self.amqp_url.starts_with("amqp://") && !self.amqp_url.starts_with("amqps://"){
+            return Err(format!("amqp_url must be an amqp:// URL, got {:?}", self.amqp_url));
+        }
+        if self.brokers < 1{return Err("brokers must be at least 1".to_string());}
+        if self.num_users < 1{return Err("num_users must be at least 1".to_string());}
+        if !self.initial_cash.is_finite() || self.initial_cash <= 0.0{return Err("initial_cash must be positive".to_string());}
+        if self.borrow_limit < 0{return Err("borrow_limit can't be negative".to_string());}
+        if self.day_rounds < 1{return Err("day_rounds must be at least 1".to_string());}
+        self.users.validate().map_err(|err| format!("users: {}", err))?;
+        if let Some(s) = self.users.strategies.iter().find(|s| s.user > self.num_users){
+            return Err(format!("users: strategies: user {} is beyond num_users", s.user));
+        }
+        self.risk.validate().map_err(|err| format!("risk: {}", err))?;
+        self.default_terms.validate().map_err(|err| format!("default_terms: {}", err))?;
+        for terms in self.broker_terms.iter(){
+            if terms.broker_no < 1{return Err("broker_terms: broker_no must be at least 1".to_string());}
+            terms.validate().map_err(|err| format!("broker_terms for broker {}: {}", terms.broker_no, err))?;
+        }
+        self.price_model.validate().map_err(|err| format!("price_model: {}", err))?;
+        self.log.validate().map_err(|err| format!("log: {}", err))?;
+        self.news.validate().map_err(|err| format!("news: {}", err))?;
+        self.candles.validate().map_err(|err| format!("candles: {}", err))?;
+
+        let names = self.queues.names();
+        if let Some(name) = names.iter().find(|name| name.is_empty()){
+            return Err(format!("queues: empty queue name {:?}", name));
+        }
+        for (i, name) in names.iter().enumerate(){
+            if names[..i].contains(name){return Err(format!("queues: {:?} is used twice", name));}
+        }
+
+        let timing = &self.timing;
+        if timing.tick_ms == 0{return Err("timing: tick_ms must be positive".to_string());}
+        for (name, timeout) in [("exchange_order_timeout_ms", timing.exchange_order_timeout_ms), ("broker_stock_list_timeout_ms", timing.broker_stock_list_timeout_ms),
+            ("broker_order_timeout_ms", timing.broker_order_timeout_ms), ("broker_execution_timeout_ms", timing.broker_execution_timeout_ms),
+            ("broker_trend_timeout_ms", timing.broker_trend_timeout_ms)]{
+            if timeout == 0{return Err(format!("timing: {} must be positive", name));}
+        }
+
+        if self.stocks.is_empty(){return Err("stocks: at least one stock must be listed".to_string());}
+        for (i, stock) in self.stocks.iter().enumerate(){
+            if stock.name.is_empty(){return Err("stocks: a stock has no name".to_string());}
+            if self.stocks[..i].iter().any(|s| s.name == stock.name){
+                return Err(format!("stocks: [{}] is listed twice", stock.name));
+            }
+            if !stock.value.is_finite() || stock.value < MIN_PRICE{
+                return Err(format!("stocks: [{}] price must be at least {}", stock.name, MIN_PRICE));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The stock universe used when no config file is given
+pub fn default_stocks() -> Vec<Stock>{
+    vec![
+        Stock{name:String::from("apl"), value:101.00},
+        Stock{name:String::from("mst"), value:91.00},
+        Stock{name:String::from("dell"), value:73.00},
+        Stock{name:String::from("ibm"), value:51.00},
+        Stock{name:String::from("petg"), value:82.00},
+        Stock{name:String::from("mly"), value:65.00},
+        Stock{name:String::from("max"), value:44.00},
+        Stock{name:String::from("gnt"), value:77.00},
+        Stock{name:String::from("airm"), value:89.00},
+        Stock{name:String::from("bbt"), value:60.00},
+        Stock{name:String::from("tpx"), value:72.00},
+        Stock{name:String::from("mmh"), value:55.00},
+        Stock{name:String::from("sunr"), value:68.00},
+        Stock{name:String::from("kct"), value:93.00},
+        Stock{name:String::from("pgg"), value:78.00},
+        Stock{name:String::from("tem"), value:81.00},
+        Stock{name:String::from("sbc"), value:47.00},
+        Stock{name:String::from("cmn"), value:64.00},
+        Stock{name:String::from("smb"), value:70.00},
+        Stock{name:String::from("jlg"), value:85.00},
+        Stock{name:String::from("cap"), value:49.00},
+        Stock{name:String::from("gmx"), value:76.00},
+        Stock{name:String::from("ant"), value:67.00},
+        Stock{name:String::from("bbl"), value:90.00},
+        Stock{name:String::from("mmc"), value:58.00},
+        Stock{name:String::from("dph"), value:71.00},
+        Stock{name:String::from("szb"), value:83.00},
+        Stock{name:String::from("fsl"), value:52.00},
+        Stock{name:String::from("pcb"), value:74.00},
+        Stock{name:String::from("sjc"), value:69.00},
+        Stock{name:String::from("sel"), value:87.00},
+        Stock{name:String::from("pwr"), value:63.00},
+        Stock{name:String::from("klh"), value:80.00},
+        Stock{name:String::from("svm"), value:54.00},
+        Stock{name:String::from("rbx"), value:86.00},
+        Stock{name:String::from("grd"), value:50.00},
+        Stock{name:String::from("tep"), value:75.00},
+        Stock{name:String::from("nmb"), value:62.00},
+        Stock{name:String::from("tlc"), value:79.00},
+        Stock{name:String::from("apm"), value:53.00},
+        Stock{name:String::from("ktm"), value:48.00},
+        Stock{name:String::from("bnt"), value:66.00},
+        Stock{name:String::from("pdm"), value:91.00},
+        Stock{name:String::from("qlt"), value:84.00},
+        Stock{name:String::from("trn"), value:61.00},
+        Stock{name:String::from("txl"), value:88.00},
+        Stock{name:String::from("mnt"), value:59.00},
+        Stock{name:String::from("kbb"), value:46.00},
+        Stock{name:String::from("snc"), value:72.00},
+        Stock{name:String::from("gmp"), value:81.00},
+        Stock{name:String::from("npx"), value:73.00},
+        Stock{name:String::from("sln"), value:54.00},
+        Stock{name:String::from("mbt"), value:67.00},
+        Stock{name:String::from("gkt"), value:92.00},
+        Stock{name:String::from("pld"), value:75.00},
+        Stock{name:String::from("nff"), value:60.00},
+        Stock{name:String::from("zmx"), value:78.00},
+        Stock{name:String::from("bpc"), value:83.00},
+        Stock{name:String::from("klb"), value:45.00},
+        Stock{name:String::from("bsn"), value:74.00},
+    ]
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn rejected(change: impl FnOnce(&mut Config)) -> String{
+        let mut config = Config::default();
+        change(&mut config);
+        config.validate().expect_err("the config should be rejected")
+    }
+
+    fn prices(stocks: &[Stock]) -> Vec<(String, f64)>{
+        stocks.iter().map(|s| (s.name.clone(), s.value)).collect()
+    }
+
+    #[test]
+    fn defaults_are_valid(){
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn default_toml_matches_the_built_in_defaults(){
+        let config = Config::load(concat!(env!("CARGO_MANIFEST_DIR"), "/config/default.toml")).unwrap();
+        assert_eq!(prices(&config.stocks), prices(&default_stocks()));
+        assert_eq!((config.brokers, config.num_users, config.initial_cash), (2, 10, INITIAL_CASH));
+    }
+
+    #[test]
+    fn counts_must_be_positive(){
+        assert_eq!(rejected(|c| c.brokers = 0), "brokers must be at least 1");
+        assert_eq!(rejected(|c| c.num_users = 0), "num_users must be at least 1");
+        assert_eq!(rejected(|c| c.day_rounds = 0), "day_rounds must be at least 1");
+        assert_eq!(rejected(|c| c.initial_cash = f64::NAN), "initial_cash must be positive");
+        assert_eq!(rejected(|c| c.amqp_url = "http://localhost".to_string()), "amqp_url must be an amqp:// URL, got \"http://localhost\"");
+    }
+
+    #[test]
+    fn stocks_must_be_unique_and_priced(){
+        assert_eq!(rejected(|c| c.stocks.push(Stock{name:"apl".to_string(), value:10.0})), "stocks: [apl] is listed twice");
+        assert_eq!(rejected(|c| c.stocks = Vec::new()), "stocks: at least one stock must be listed");
+        assert_eq!(rejected(|c| c.stocks[0].value = 0.0), format!("stocks: [apl] price must be at least {}", MIN_PRICE));
+    }
+
+    #[test]
+    fn timings_and_queues_are_checked(){
+        assert_eq!(rejected(|c| c.timing.tick_ms = 0), "timing: tick_ms must be positive");
+        assert_eq!(rejected(|c| c.timing.broker_order_timeout_ms = 0), "timing: broker_order_timeout_ms must be positive");
+        assert_eq!(rejected(|c| c.queues.session = c.queues.executions.clone()), "queues: \"executions\" is used twice");
+        assert!(rejected(|c| c.queues.registry = String::new()).starts_with("queues: empty queue name"));
+    }
+
+    #[test]
+    fn sections_report_where_they_failed(){
+        assert!(rejected(|c| c.users.sell_prob = 2.0).starts_with("users: "));
+        assert!(rejected(|c| c.risk.max_notional = -1.0).starts_with("risk: "));
+        assert!(rejected(|c| c.broker_terms[0].broker_no = 0).starts_with("broker_terms: "));
+    }
+}
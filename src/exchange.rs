@@ -0,0 +1,447 @@
+use std::{collections::{BTreeSet, HashMap}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+use crossbeam_channel::Sender;
+use lazy_static::lazy_static;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use crate::candles;
+use crate::config::Config;
+use crate::journal::{self, Event};
+use crate::logging::{self, Component, Tone};
+use crate::metrics;
+use crate::market_data::Feed;
+use crate::news::{News, NewsEngine};
+use crate::message::{decode, decode_with, BookNotice, BrokerControl, CancelOrder, EndOfSession, ExchangeRequest, Execution, FinalReport, LimitOrder, MarketData, OrderType, Side, SnapshotRequest, TimeInForce};
+use crate::order::now_millis;
+use crate::orderbook::{to_ticks, OrderBook};
+use crate::price_model::{PriceModel, MIN_PRICE};
+use crate::session::{self, Session};
+use crate::transport::{Result, Subscription, Transport};
+use crate::Stock;
+
+// The exchange's own market-making account, quoting both sides of every book
+// at the current price so users and brokers always have a counterparty
+pub const HOUSE_BROKER: i8 = 0;
+pub const HOUSE_DEPTH: i128 = 1000;
+
+// (stock name, new price) published to the brokers
+pub type StockTrend = (String,f64);
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct  StockProfile{
+    pub name:String,
+    pub cur_price: f64,
+    pub sold_vol:i128,
+    pub buy_vol: i128,
+}
+
+#[allow(dead_code)]
+#[warn(unused_mut,unreachable_code)]
+impl StockProfile {
+    // Update buy & sell vol
+    pub fn add_stock_profile(name: String, sold_vol: i128, buy_vol: i128) {
+        journal::record("exchange", Event::VolumeAdded{stock_name:name.clone(), sold_vol, buy_vol});
+        let mut profiles = STOCK_PROFILES.lock().unwrap();
+        let stocklist = STOCK_LIST.lock().unwrap(); 
+        add_volume(&mut profiles, &stocklist, &name, sold_vol, buy_vol);
+    }
+
+    // Add a headline's volume to every stock it hit, opening profiles at the listed price
+    pub fn apply_news(news: &News){
+        let (sold_vol, buy_vol) = news.headline.volume();
+        journal::record("exchange", Event::NewsReleased{headline:news.headline.text.clone(), sentiment:news.headline.sentiment,
+            scope:news.headline.scope.to_string(), affected:news.affected.clone(), sold_vol, buy_vol});
+        let mut profiles = STOCK_PROFILES.lock().unwrap();
+        let stocklist = STOCK_LIST.lock().unwrap();
+        for name in news.affected.iter(){
+            add_volume(&mut profiles, &stocklist, name, sold_vol, buy_vol);
+        }
+    }
+
+    // Detect up & down trends, the price model decides how volume moves the price
+    pub fn detect_trend(price_model: &mut dyn PriceModel) -> (Vec<StockTrend>,Vec<StockTrend>){
+        let mut profiles = STOCK_PROFILES.lock().unwrap();
+        let mut stock_up_trend: Vec<StockTrend> = Vec::new();
+        let mut stock_down_trend: Vec<StockTrend> = Vec::new();
+        for stock in profiles.iter_mut(){
+            let price_move = price_model.next_price(stock.cur_price, stock.buy_vol, stock.sold_vol);
+            stock.buy_vol-=price_move.buy_used; // minus back the converted num of stocks
+            stock.sold_vol-=price_move.sold_used;
+            let new_stock_price = price_move.price.max(MIN_PRICE); // never let a stock reach zero
+            let moved = to_ticks(new_stock_price) - to_ticks(stock.cur_price);
+            if moved != 0 || price_move.buy_used != 0 || price_move.sold_used != 0{
+                journal::record("exchange", Event::PriceChanged{stock_name:stock.name.clone(), price:new_stock_price,
+                    buy_used:price_move.buy_used, sold_used:price_move.sold_used});
+            }
+            stock.cur_price = new_stock_price;
+            if moved > 0{
+                stock_up_trend.push((stock.name.clone(),new_stock_price));
+            }else if moved < 0{
+                stock_down_trend.push((stock.name.clone(),new_stock_price));
+            }
+        }
+        (stock_up_trend,stock_down_trend)
+    }
+}
+
+// Add traded volume to a stock's profile, opening it at the listed price on its first trade.
+// Shared by the live profiles and the journal's rebuild.
+pub(crate) fn add_volume(profiles: &mut Vec<StockProfile>, stocklist: &[Stock], name: &str, sold_vol: i128, buy_vol: i128){
+    if let Some(stock) = profiles.iter_mut().find(|p| p.name == name){
+        stock.buy_vol+=buy_vol;
+        stock.sold_vol+=sold_vol;
+    }else if let Some(listed) = stocklist.iter().find(|s| s.name == name){
+        // if the stock havent been purchased before
+        profiles.push(StockProfile{name:name.to_string(),cur_price:listed.value,sold_vol,buy_vol});
+    }
+}
+
+lazy_static! {
+    static ref STOCK_PROFILES: Arc<Mutex<Vec<StockProfile>>> = Arc::new(Mutex::new(Vec::new()));
+}
+
+lazy_static! {
+    // listed from the config when the exchange opens
+    pub static ref STOCK_LIST: Arc<Mutex<Vec<Stock>>> = Arc::new(Mutex::new(Vec::new()));
+}
+
+#[allow(dead_code)]
+impl STOCK_LIST {
+    // List the stocks at their initial prices, unless they already are
+    pub fn list_stocks(stocks: &[Stock]){
+        let mut listed = STOCK_LIST.lock().unwrap();
+        if listed.is_empty(){
+            journal::record("exchange", Event::StockListed{stocks:stocks.to_vec()});
+            for stock in stocks{
+                metrics::set(&metrics::PRICE, &[("symbol", &stock.name)], stock.value);
+                candles::record(&stock.name, now_millis(), stock.value, 0);
+            }
+            listed.extend_from_slice(stocks);
+        }
+    }
+
+    // Update the original stock price list
+    pub fn update_stock_price(name:String){
+        let mut stocks = STOCK_LIST.lock().unwrap();
+        let profiles = STOCK_PROFILES.lock().unwrap();
+        for s in stocks.iter_mut(){
+            if s.name == name{
+                for p in profiles.iter(){
+                    if p.name == name{
+                        journal::record("exchange", Event::ListPriceUpdated{stock_name:name.clone(), price:p.cur_price});
+                        metrics::set(&metrics::PRICE, &[("symbol", &name)], p.cur_price);
+                        candles::record(&name, now_millis(), p.cur_price, 0);
+                        s.value = p.cur_price;
+                    }
+                }
+            }
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref ORDER_BOOKS: Arc<Mutex<HashMap<String, OrderBook>>> = Arc::new(Mutex::new(HashMap::new()));
+}
+
+impl ORDER_BOOKS {
+    // Match a broker's order on its symbol's book, with the notice for what was
+    // cancelled instead of resting
+    pub fn submit_order(order: LimitOrder) -> (Vec<Execution>, Option<BookNotice>){
+        journal::record("exchange", Event::OrderSubmitted{order:order.clone()});
+        let mut books = ORDER_BOOKS.lock().unwrap();
+        let book = books.entry(order.stock_name.clone()).or_insert_with(|| OrderBook::new(order.stock_name.clone()));
+        let executions = book.submit(order.clone());
+        let filled_qty: i128 = executions.iter().filter(|e| e.aggressor).map(|e| e.num_stock).sum();
+        if order.rests() || filled_qty == order.num_stock{return (executions, None);}
+        let reason = match (order.order_type, order.time_in_force){
+            (_, TimeInForce::Fok) => "fill or kill: not enough liquidity",
+            (OrderType::Market, _) => "market order: no more liquidity",
+            _ => "immediate or cancel: the rest didn't fill",
+        };
+        let notice = BookNotice::Cancelled{order_id:order.order_id, broker_no:order.broker_no, user_id:order.user_id, stock_name:order.stock_name,
+            filled_qty, cancelled_qty:order.num_stock - filled_qty, reason:reason.to_string()};
+        (executions, Some(notice))
+    }
+
+    // Take a broker's resting order off the book on request. Too late once it has filled
+    // or for an order that never rested
+    pub fn cancel_order(request: CancelOrder) -> BookNotice{
+        journal::record("exchange", Event::CancelSubmitted{request:request.clone()});
+        let mut books = ORDER_BOOKS.lock().unwrap();
+        let cancelled = books.get_mut(&request.stock_name).map(|book| book.cancel_where(|o| o.order_id == request.order_id && o.broker_no == request.broker_no))
+            .and_then(|cancelled| cancelled.into_iter().next());
+        match cancelled{
+            Some((order, leaves_qty)) => BookNotice::Cancelled{order_id:order.order_id, broker_no:order.broker_no, user_id:order.user_id, stock_name:order.stock_name,
+                filled_qty:order.num_stock - leaves_qty, cancelled_qty:leaves_qty, reason:"cancelled on request".to_string()},
+            None => BookNotice::CancelRejected{order_id:request.order_id, broker_no:request.broker_no, reason:"not on the book".to_string()},
+        }
+    }
+
+    // Cancel the DAY orders still resting at the close of a trading day
+    pub fn close_day() -> Vec<BookNotice>{
+        let mut books = ORDER_BOOKS.lock().unwrap();
+        let mut notices = Vec::new();
+        for book in books.values_mut(){
+            for (order, leaves_qty) in book.cancel_where(|o| o.time_in_force == TimeInForce::Day){
+                notices.push(BookNotice::Cancelled{order_id:order.order_id, broker_no:order.broker_no, user_id:order.user_id, stock_name:order.stock_name,
+                    filled_qty:order.num_stock - leaves_qty, cancelled_qty:leaves_qty, reason:"day order expired".to_string()});
+            }
+        }
+        notices
+    }
+
+    // Replace the house quotes on a symbol with fresh ones at the given price.
+    // The new quotes can hit users' resting orders, so executions come back too.
+    pub fn quote_house(name: &str, price: f64) -> Vec<Execution>{
+        let mut books = ORDER_BOOKS.lock().unwrap();
+        let book = books.entry(name.to_string()).or_insert_with(|| OrderBook::new(name.to_string()));
+        book.cancel_where(|o| o.broker_no == HOUSE_BROKER);
+        let mut executions = Vec::new();
+        for side in [Side::Buy, Side::Sell]{
+            let quote = LimitOrder{order_id:"house".to_string(), broker_no:HOUSE_BROKER, user_id:0, stock_name:name.to_string(), side, price, num_stock:HOUSE_DEPTH,
+                order_type:OrderType::Limit, time_in_force:TimeInForce::Gtc};
+            executions.extend(book.submit(quote));
+        }
+        executions
+    }
+}
+
+// Send every non-house execution out to the brokers and feed the aggressor's
+// volume into the stock profile
+fn publish_executions(transport: &dyn Transport, config: &Config, executions: Vec<Execution>) -> Result<()>{
+    for exec in executions{
+        // each match has one aggressor, so a trade is only counted once
+        if exec.aggressor{candles::record(&exec.stock_name, now_millis(), exec.price, exec.num_stock);}
+        if exec.broker_no == HOUSE_BROKER{continue;}
+        metrics::inc(&metrics::FILLS, &[("component", "exchange")]);
+        journal::record("exchange", Event::Fill{execution:exec.clone()});
+        if exec.aggressor{
+            match exec.side{
+                Side::Buy => StockProfile::add_stock_profile(exec.stock_name.clone(), 0, exec.num_stock),
+                Side::Sell => StockProfile::add_stock_profile(exec.stock_name.clone(), exec.num_stock, 0),
+            }
+        }
+        // every broker gets a copy so users' portfolios add up across brokers
+        let exec_json = serde_json::to_string(&exec).expect("Failed to serialize");
+        transport.broadcast(&config.queues.executions, exec_json.as_bytes())?;
+    }
+    Ok(())
+}
+
+// Tell the brokers about orders taken off the book
+fn publish_notice(transport: &dyn Transport, config: &Config, notice: &BookNotice) -> Result<()>{
+    journal::record("exchange", Event::OrderNotice{notice:notice.clone()});
+    match notice{
+        BookNotice::Cancelled{order_id, broker_no, user_id, stock_name, cancelled_qty, reason, ..} => {
+            logging::info(Component::Exchange, format!("cancelled {} units of order {} from broker {}: {}", cancelled_qty, order_id, broker_no, reason))
+                .field("order_id", order_id).field("broker", broker_no).user(*user_id).symbol(stock_name).field("quantity", cancelled_qty).field("reason", reason).emit();
+        }
+        BookNotice::CancelRejected{order_id, broker_no, reason} => {
+            logging::info(Component::Exchange, format!("couldn't cancel order {} from broker {}: {}", order_id, broker_no, reason))
+                .field("order_id", order_id).field("broker", broker_no).field("reason", reason).emit();
+        }
+        BookNotice::DayClosed{..} => {}
+    }
+    let notice_json = serde_json::to_string(notice).expect("Failed to serialize");
+    transport.broadcast(&config.queues.order_notices, notice_json.as_bytes())
+}
+
+// Broadcast market data on one of its fanouts, snapshots & heartbeats or updates
+fn publish_market_data(transport: &dyn Transport, exchange: &str, message: &MarketData) -> Result<()>{
+    journal::record("exchange", Event::MarketDataPublished{message:message.clone()});
+    let message_json = serde_json::to_string(message).expect("Failed to serialize");
+    transport.broadcast(exchange, message_json.as_bytes())
+}
+
+// Apply the brokers' register & deregister messages waiting on the registry
+fn update_registry(registry: &dyn Subscription, registered: &mut BTreeSet<i8>) -> Result<()>{
+    while let Some(body) = registry.recv_timeout(Duration::ZERO)?{
+        let Some(control) = decode(Component::Exchange, &body) else {continue};
+        match control{
+            BrokerControl::Register{broker_no} => {
+                if registered.insert(broker_no){logging::info(Component::Exchange, format!("Broker {} registered", broker_no)).field("broker", broker_no).emit();}
+            }
+            BrokerControl::Deregister{broker_no} => {
+                if registered.remove(&broker_no){logging::info(Component::Exchange, format!("Broker {} deregistered", broker_no)).field("broker", broker_no).emit();}
+            }
+        }
+    }
+    Ok(())
+}
+
+// Tell the brokers the session is over and wait for their final reports
+fn close_session(transport: &dyn Transport, config: &Config, registry: &dyn Subscription, registered: &mut BTreeSet<i8>, final_reports: &dyn Subscription, reason: &str) -> Result<()>{
+    logging::info(Component::Exchange, format!("Closing the session ({})", reason)).field("reason", reason).emit();
+    journal::record("exchange", Event::SessionEnded{reason:reason.to_string()});
+    let end = EndOfSession{reason:reason.to_string(), timestamp:now_millis()};
+    transport.broadcast(&config.queues.session, serde_json::to_string(&end).expect("Failed to serialize").as_bytes())?;
+    // brokers send their report before deregistering, so even those already gone are waited for
+    let mut waiting = registered.clone();
+    let deadline = Instant::now() + Duration::from_millis(config.timing.shutdown_drain_ms);
+    while !waiting.is_empty(){
+        let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+            logging::warn(Component::Exchange, format!("no final report from broker(s) {:?}", waiting)).field("brokers", &waiting).emit();
+            break;
+        };
+        let Some(body) = final_reports.recv_timeout(left)? else {continue};
+        let Some(report) = decode::<FinalReport>(Component::Exchange, &body) else {continue};
+        logging::info(Component::Exchange, format!("Broker {} closed - {} orders cancelled, {} open positions flushed, {} portfolios",
+            report.broker_no, report.cancelled.len(), report.open_positions.len(), report.portfolios.len()))
+            .field("broker", report.broker_no).field("cancelled", report.cancelled.len()).field("open_positions", report.open_positions.len()).emit();
+        waiting.remove(&report.broker_no);
+    }
+    update_registry(registry, registered)?;
+    Ok(())
+}
+
+// Exchanges or Broadcaster thread body, returns once the session is closed
+pub fn run(transport: &dyn Transport, config: &Config, price_model: &mut dyn PriceModel, rng: &mut StdRng, sl_tx: &Sender<Arc<Mutex<Vec<Stock>>>>, session: &Session) -> Result<()>{
+    // Headlines that may break each round
+    let news_engine = NewsEngine::new(&config.news).unwrap_or_else(|err| panic!("Invalid news: {}", err));
+
+    // Exchange round, news starts from `news.start_round`
+    let mut round = 0;
+
+    let queues = &config.queues;
+    let timing = &config.timing;
+    let num_users = config.num_users as i32;
+
+    /* ---------------------- Exchange receiver--------------------- */
+    // Define queue for exchange to receive brokers' orders
+    let ex_order_recv = transport.consume(&queues.exchange_orders)?;
+    // brokers come and go, the stock list, trends and fills are broadcast to whoever is registered
+    let registry = transport.consume(&queues.registry)?;
+    let mut registered: BTreeSet<i8> = BTreeSet::new();
+    // brokers' last reports once the session is over
+    let final_reports = transport.subscribe(&queues.final_reports)?;
+    // brokers that missed market data updates
+    let snapshot_requests = transport.consume(&queues.snapshot_requests)?;
+    let mut feed = Feed::default();
+    let mut snapshot_due = true; // every session opens with one
+
+    // Open every book with the house quotes at the listed price
+    candles::configure(&config.candles);
+    STOCK_LIST::list_stocks(&config.stocks);
+    let listed = STOCK_LIST.lock().unwrap().clone();
+    for stock in listed.iter(){
+        ORDER_BOOKS::quote_house(&stock.name, stock.value);
+    }
+
+    let reason = loop{
+        if session::stop_requested(){break "interrupted";}
+        round+=1;
+
+        // Send list for customer
+        logging::debug(Component::Exchange, "Stock list publishing..").emit();
+        thread::sleep(Duration::from_millis(timing.exchange_publish_delay_ms));
+        if session.visited() < num_users{sl_tx.send(STOCK_LIST.clone()).unwrap();} // prevent threads panic
+
+        // Send the brokers a snapshot if one is due, otherwise only the last update's number
+        update_registry(&*registry, &mut registered)?;
+        while let Some(body) = snapshot_requests.recv_timeout(Duration::ZERO)?{
+            let Some(request) = decode::<SnapshotRequest>(Component::Exchange, &body) else {continue};
+            logging::debug(Component::Exchange, format!("Broker {} asked for a snapshot after update {:?}", request.broker_no, request.last_seq))
+                .field("broker", request.broker_no).field("last_seq", request.last_seq).emit();
+            snapshot_due = true;
+        }
+        if snapshot_due{
+            let vec_stock_list = STOCK_LIST.lock().unwrap().clone();
+            publish_market_data(transport, &queues.stock_info, &feed.snapshot(&vec_stock_list))?;
+            logging::info(Component::Exchange, format!("Had send stock list to brokers {:?}", registered)).field("brokers", &registered).emit();
+            snapshot_due = false;
+        }else{
+            let heartbeat = feed.heartbeat();
+            publish_market_data(transport, &queues.stock_info, &heartbeat)?;
+            logging::debug(Component::Exchange, format!("Heartbeat at update {} to brokers {:?}", heartbeat.seq(), registered)).field("brokers", &registered).emit();
+        }
+
+        // Match brokers' buy & sell orders on the books
+        let timeout_order_monitor = Duration::from_millis(timing.exchange_order_timeout_ms);
+        logging::debug(Component::Exchange, "Matching new orders..").emit();
+        let mut got_orders = false;
+        while let Some(body) = ex_order_recv.recv_timeout(timeout_order_monitor)?{
+            got_orders = true;
+            metrics::inc(&metrics::ORDERS_RECEIVED, &[("component", "exchange")]);
+            // a new order or a cancel for one
+            let Some(request) = decode_with(Component::Exchange, &body, ExchangeRequest::from_slice) else {continue};
+            let (order_id, broker_no, user_id, stock_name) = match &request{
+                ExchangeRequest::Order(order) => (order.order_id.clone(), order.broker_no, order.user_id, order.stock_name.clone()),
+                ExchangeRequest::Cancel(cancel) => (cancel.order_id.clone(), cancel.broker_no, cancel.user_id, cancel.stock_name.clone()),
+            };
+            if !registered.contains(&broker_no){
+                update_registry(&*registry, &mut registered)?;
+                if !registered.contains(&broker_no){
+                    logging::warn(Component::Exchange, format!("ignored order {} from unregistered broker {}", order_id, broker_no))
+                        .field("order_id", &order_id).field("broker", broker_no).user(user_id).symbol(&stock_name).emit();
+                    metrics::inc(&metrics::REJECTIONS, &[("component", "exchange"), ("reason", "unregistered broker")]);
+                    continue;
+                }
+            }
+            match request{
+                ExchangeRequest::Order(order) => {
+                    let (executions, notice) = ORDER_BOOKS::submit_order(order);
+                    publish_executions(transport, config, executions)?;
+                    if let Some(notice) = notice{publish_notice(transport, config, &notice)?;}
+                }
+                ExchangeRequest::Cancel(cancel) => publish_notice(transport, config, &ORDER_BOOKS::cancel_order(cancel))?,
+            }
+            if session::stop_requested(){break;}
+        }
+        logging::debug(Component::Exchange, "order matching: Timeout reached. No message received.").emit();
+
+        // Breaking news adds sold volume to the stocks it hits when it's bad, bought volume when it's good
+        let listed = STOCK_LIST.lock().unwrap().clone();
+        for news in news_engine.draw(round, &listed, rng){
+            let headline = &news.headline;
+            let message_length = (headline.text.to_owned()+" Exchange: Breaking news!! ").chars().count();
+            // Draw the news in a box
+            let border = format!("--{}--", "-".repeat(message_length + 4));
+            let affected: String = news.affected.iter().map(|s| format!("[{}]  ", s)).collect();
+            let (sold_vol, buy_vol) = headline.volume();
+            logging::info(Component::Exchange, format!("Breaking news!! {}", headline.text))
+                .field("headline", &headline.text).field("sentiment", headline.sentiment).field("scope", headline.scope.to_string())
+                .field("affected", &news.affected).field("sold_vol", sold_vol).field("buy_vol", buy_vol)
+                .display(format!("{}\n|  Exchange: Breaking news!! {}  |\n{}\n--  Affected stock: {}--", border, headline.text, border, affected)).emit();
+            StockProfile::apply_news(&news);
+        }
+
+        // Check up & down trends
+        let (up_stock_list, down_trend_stock) = StockProfile::detect_trend(price_model);
+        logging::debug(Component::Exchange, "Currently checking on uptrend...").emit();
+        thread::sleep(Duration::from_millis(timing.exchange_trend_delay_ms));
+        for stock in up_stock_list.iter(){
+            logging::info(Component::Exchange, format!("Stock [{}] was on fire!! - current price: {}", stock.0, stock.1.round()))
+                .symbol(&stock.0).price(stock.1).field("trend", "up").tone(Tone::Good).timed().emit();
+            // update STOCK_LIST price & re-quote the book
+            STOCK_LIST::update_stock_price((stock.0).clone());
+            publish_executions(transport, config, ORDER_BOOKS::quote_house(&stock.0, stock.1))?;
+            // send uptrend info to the brokers
+            publish_market_data(transport, &queues.stock_trending, &feed.update(&stock.0, stock.1))?;
+        }
+
+        logging::debug(Component::Exchange, "Currently checking on downstrend...").emit();
+        for stock in down_trend_stock.iter(){
+            logging::info(Component::Exchange, format!("Stock [{}] was dropping!! - current price: {}", stock.0, stock.1.round()))
+                .symbol(&stock.0).price(stock.1).field("trend", "down").tone(Tone::Bad).timed().emit();
+            // update stock price & re-quote the book
+            STOCK_LIST::update_stock_price((stock.0).clone());
+            publish_executions(transport, config, ORDER_BOOKS::quote_house(&stock.0, stock.1))?;
+            // send downtrend info to the brokers
+            publish_market_data(transport, &queues.stock_trending, &feed.update(&stock.0, stock.1))?;
+        }
+        // DAY orders don't outlive the trading day
+        if round % config.day_rounds == 0{
+            let day = round / config.day_rounds;
+            let notices = ORDER_BOOKS::close_day();
+            logging::info(Component::Exchange, format!("Trading day {} closed, {} DAY orders expired", day, notices.len())).field("day", day).field("expired", notices.len()).emit();
+            for notice in notices.iter(){publish_notice(transport, config, notice)?;}
+            publish_notice(transport, config, &BookNotice::DayClosed{day})?;
+        }
+
+        //  Last round check before ending the exchange threads
+        if !got_orders && session.users_done(){
+            logging::info(Component::Exchange, "There isn't have any update on stocks' orders").emit();
+            break "no more orders";
+        }
+    };
+    close_session(transport, config, &*registry, &mut registered, &*final_reports, reason)
+}
@@ -0,0 +1,284 @@
+use std::{fs::{File, OpenOptions}, io::{self, Read, Write}, path::Path, sync::Mutex};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use crate::exchange::{add_volume, StockProfile};
+use crate::logging::{self, Component};
+use crate::message::{BookNotice, CancelOrder, Execution, LimitOrder, MarketData, Order, OrderControl};
+use crate::order::now_millis;
+use crate::purchase::{amend_records, flush_records, merge_order, raise_high_water, reduce_records, PurchaseDetails, SellReason};
+use crate::Stock;
+
+// Everything that changes the exchange's or the brokers' state, in the order it happened.
+// Replaying the events rebuilds STOCK_LIST, STOCK_PROFILES and PURCHASE_HISTORY.
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub enum Event{
+    StockListed{stocks:Vec<Stock>},
+    OrderReceived{broker_no:i8, order:Order}, // user's order reaching a broker
+    OrderSubmitted{order:LimitOrder}, // broker's order reaching the book
+    Fill{execution:Execution},
+    VolumeAdded{stock_name:String, sold_vol:i128, buy_vol:i128},
+    PriceChanged{stock_name:String, price:f64, buy_used:i128, sold_used:i128},
+    ListPriceUpdated{stock_name:String, price:f64},
+    PositionAdded{details:PurchaseDetails},
+    PositionReduced{broker_no:i8, user_id:i8, stock_name:String, num_stock:i128},
+    SellTriggered{broker_no:i8, user_id:i8, stock_name:String, price:f64, num_stock:i128, reason:SellReason},
+    PositionsFlushed{broker_no:i8}, // broker shut down, its holdings aren't watched anymore
+    SessionEnded{reason:String},
+    MarketDataPublished{message:MarketData}, // snapshot, update or heartbeat broadcast to the brokers
+    NewsReleased{headline:String, sentiment:f64, scope:String, affected:Vec<String>, sold_vol:i128, buy_vol:i128}, // volumes added per affected stock
+    OrderNotice{notice:BookNotice}, // order cancelled by the book or a trading day closed
+    OrderControlReceived{broker_no:i8, control:OrderControl}, // user's cancel, replace or amend reaching a broker
+    CancelSubmitted{request:CancelOrder}, // broker's cancel reaching the book
+    LevelsAmended{broker_no:i8, user_id:i8, stock_name:String, take_profit:Option<f64>, cut_loss:Option<f64>},
+    HighWaterRaised{broker_no:i8, user_id:i8, stock_name:String, high_water:f64}, // a holding's trailing stop followed a new high
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct JournalEntry{
+    pub seq:u64, // per journal file, from 0
+    pub timestamp:i64, // ms since epoch
+    pub source:String, // "exchange" or "broker <n>"
+    pub event:Event,
+}
+
+// `.jsonl` files get one JSON entry per line, anything else the compact binary encoding:
+// a little-endian u32 length then the bincode bytes of the entry
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum JournalFormat{
+    JsonLines,
+    Binary,
+}
+
+impl JournalFormat{
+    pub fn for_path(path: &str) -> JournalFormat{
+        match Path::new(path).extension().and_then(|ext| ext.to_str()){
+            Some("jsonl") => JournalFormat::JsonLines,
+            _ => JournalFormat::Binary,
+        }
+    }
+}
+
+struct Journal{
+    file: File,
+    format: JournalFormat,
+    next_seq: u64,
+}
+
+lazy_static! {
+    // nothing is recorded until a journal is opened
+    static ref JOURNAL: Mutex<Option<Journal>> = Mutex::new(None);
+}
+
+// Start recording to `path`. The journal is append-only, an existing one carries on
+// from its last sequence number.
+pub fn open(path: &str) -> io::Result<()>{
+    let format = JournalFormat::for_path(path);
+    let next_seq = if Path::new(path).exists(){
+        read(path)?.last().map(|entry| entry.seq + 1).unwrap_or(0)
+    }else{
+        0
+    };
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *JOURNAL.lock().unwrap() = Some(Journal{file, format, next_seq});
+    Ok(())
+}
+
+// Append an event, a no-op when no journal is open
+pub fn record(source: &str, event: Event){
+    let mut journal = JOURNAL.lock().unwrap();
+    let Some(journal) = journal.as_mut() else {return};
+    let entry = JournalEntry{seq:journal.next_seq, timestamp:now_millis(), source:source.to_string(), event};
+    let bytes = encode(&entry, journal.format);
+    // each entry goes out in one write, so a crash never leaves half of one behind
+    match journal.file.write_all(&bytes){
+        Ok(()) => journal.next_seq+=1,
+        Err(err) => logging::error(Component::Named("Journal"), format!("failed to record event {}: {}", entry.seq, err)).field("seq", entry.seq).emit(),
+    }
+}
+
+pub fn encode(entry: &JournalEntry, format: JournalFormat) -> Vec<u8>{
+    match format{
+        JournalFormat::JsonLines => {
+            let mut line = serde_json::to_vec(entry).expect("Failed to serialize");
+            line.push(b'\n');
+            line
+        }
+        JournalFormat::Binary => {
+            let body = bincode::serialize(entry).expect("Failed to serialize");
+            let mut record = (body.len() as u32).to_le_bytes().to_vec();
+            record.extend(body);
+            record
+        }
+    }
+}
+
+// Every entry of a journal file, in the order they were recorded
+pub fn read(path: &str) -> io::Result<Vec<JournalEntry>>{
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let invalid = |err: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, err));
+    let mut entries = Vec::new();
+    match JournalFormat::for_path(path){
+        JournalFormat::JsonLines => {
+            for (no, line) in String::from_utf8_lossy(&bytes).lines().enumerate(){
+                if line.trim().is_empty(){continue;}
+                entries.push(serde_json::from_str(line).map_err(|err| invalid(format!("line {}: {}", no+1, err)))?);
+            }
+        }
+        JournalFormat::Binary => {
+            let mut rest = &bytes[..];
+            while !rest.is_empty(){
+                if rest.len() < 4{return Err(invalid("truncated record length".to_string()));}
+                let len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+                if rest.len() < 4 + len{return Err(invalid(format!("truncated record after seq {:?}", entries.last().map(|e: &JournalEntry| e.seq))));}
+                entries.push(bincode::deserialize(&rest[4..4+len]).map_err(|err| invalid(err.to_string()))?);
+                rest = &rest[4+len..];
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// State rebuilt from a journal
+#[derive(Clone,Debug,Default)]
+pub struct Rebuilt{
+    pub stock_list: Vec<Stock>,
+    pub profiles: Vec<StockProfile>,
+    pub purchase_history: Vec<PurchaseDetails>,
+}
+
+// Replay the entries in order, the same way the exchange & brokers applied them
+pub fn rebuild(entries: &[JournalEntry]) -> Rebuilt{
+    let mut state = Rebuilt::default();
+    for entry in entries{
+        match &entry.event{
+            Event::StockListed{stocks} => {
+                if state.stock_list.is_empty(){state.stock_list = stocks.clone();}
+            }
+            Event::VolumeAdded{stock_name, sold_vol, buy_vol} => {
+                add_volume(&mut state.profiles, &state.stock_list, stock_name, *sold_vol, *buy_vol);
+            }
+            Event::NewsReleased{affected, sold_vol, buy_vol, ..} => {
+                for name in affected{
+                    add_volume(&mut state.profiles, &state.stock_list, name, *sold_vol, *buy_vol);
+                }
+            }
+            Event::PriceChanged{stock_name, price, buy_used, sold_used} => {
+                if let Some(p) = state.profiles.iter_mut().find(|p| &p.name == stock_name){
+                    p.buy_vol-=buy_used;
+                    p.sold_vol-=sold_used;
+                    p.cur_price = *price;
+                }
+            }
+            Event::ListPriceUpdated{stock_name, price} => {
+                if let Some(s) = state.stock_list.iter_mut().find(|s| &s.name == stock_name){s.value = *price;}
+            }
+            Event::PositionAdded{details} => merge_order(&mut state.purchase_history, details.clone()),
+            Event::PositionReduced{broker_no, user_id, stock_name, num_stock} => {
+                reduce_records(&mut state.purchase_history, *broker_no, *user_id, stock_name, *num_stock);
+            }
+            Event::SellTriggered{broker_no, user_id, stock_name, ..} => {
+                state.purchase_history.retain(|d| !(d.broker_no == *broker_no && d.id == *user_id && &d.stock_name == stock_name));
+            }
+            Event::PositionsFlushed{broker_no} => {flush_records(&mut state.purchase_history, *broker_no);}
+            Event::LevelsAmended{broker_no, user_id, stock_name, take_profit, cut_loss} => {
+                // only amends the broker accepted are recorded
+                let _ = amend_records(&mut state.purchase_history, *broker_no, *user_id, stock_name, *take_profit, *cut_loss);
+            }
+            Event::HighWaterRaised{broker_no, user_id, stock_name, high_water} => {
+                raise_high_water(&mut state.purchase_history, *broker_no, *user_id, stock_name, *high_water);
+            }
+            // orders and fills only change state through the events they cause
+            Event::OrderReceived{..} | Event::OrderSubmitted{..} | Event::Fill{..} | Event::OrderNotice{..}
+                | Event::OrderControlReceived{..} | Event::CancelSubmitted{..} => {}
+            // only what the brokers were sent, see replay.rs
+            Event::MarketDataPublished{..} | Event::SessionEnded{..} => {}
+        }
+    }
+    state
+}
+
+// Entries of several journals (the exchange's and each broker process's) in time order
+pub fn merge(journals: Vec<Vec<JournalEntry>>) -> Vec<JournalEntry>{
+    let mut entries: Vec<JournalEntry> = journals.into_iter().flatten().collect();
+    // stable, so entries of one file stamped in the same ms keep their order
+    entries.sort_by_key(|entry| entry.timestamp);
+    entries
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{env, fs, process};
+
+    fn entry(seq: u64, event: Event) -> JournalEntry{
+        JournalEntry{seq, timestamp:1_000 + seq as i64, source:"broker 1".to_string(), event}
+    }
+
+    fn holding(num_stock: i128) -> PurchaseDetails{
+        PurchaseDetails{broker_no:1, id:3, stock_name:"AAPL".to_string(), take_profit:120.0, cut_loss:90.0, num_stock,
+            reply_to:"user_reports".to_string(), trailing_stop:None, high_water:100.0, expires_at:None}
+    }
+
+    fn entries() -> Vec<JournalEntry>{
+        vec![
+            entry(0, Event::StockListed{stocks:vec![Stock{name:"AAPL".to_string(), value:100.0}]}),
+            entry(1, Event::ListPriceUpdated{stock_name:"AAPL".to_string(), price:104.0}),
+            entry(2, Event::PositionAdded{details:holding(10)}),
+            entry(3, Event::PositionAdded{details:holding(5)}),
+            entry(4, Event::PositionReduced{broker_no:1, user_id:3, stock_name:"AAPL".to_string(), num_stock:4}),
+            entry(5, Event::LevelsAmended{broker_no:1, user_id:3, stock_name:"AAPL".to_string(), take_profit:Some(130.0), cut_loss:None}),
+        ]
+    }
+
+    // Write the entries as the journal would and read them back
+    fn round_trip(name: &str, extension: &str) -> Vec<JournalEntry>{
+        let path = env::temp_dir().join(format!("stock-sim-journal-{}-{}.{}", process::id(), name, extension));
+        let path = path.to_str().unwrap();
+        let bytes: Vec<u8> = entries().iter().flat_map(|e| encode(e, JournalFormat::for_path(path))).collect();
+        fs::write(path, bytes).unwrap();
+        let read = read(path);
+        fs::remove_file(path).unwrap();
+        read.unwrap()
+    }
+
+    #[test]
+    fn entries_read_back_in_both_formats(){
+        for extension in ["jsonl", "bin"]{
+            let read = round_trip("formats", extension);
+            assert_eq!(read.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5], "{}", extension);
+            assert!(matches!(&read[4].event, Event::PositionReduced{num_stock:4, ..}), "{}", extension);
+        }
+    }
+
+    #[test]
+    fn rebuild_replays_the_state_changes(){
+        let state = rebuild(&round_trip("rebuild", "jsonl"));
+        assert_eq!(state.stock_list[0].value, 104.0);
+        assert_eq!(state.purchase_history.len(), 1);
+        let d = &state.purchase_history[0];
+        assert_eq!((d.num_stock, d.take_profit, d.cut_loss), (11, 130.0, 90.0));
+        let mut sold = entries();
+        sold.push(entry(6, Event::SellTriggered{broker_no:1, user_id:3, stock_name:"AAPL".to_string(), price:89.0, num_stock:11, reason:SellReason::CutLoss}));
+        assert!(rebuild(&sold).purchase_history.is_empty());
+    }
+
+    #[test]
+    fn rebuild_follows_the_trailing_stop_high(){
+        let mut raised = entries();
+        raised.push(entry(6, Event::HighWaterRaised{broker_no:1, user_id:3, stock_name:"AAPL".to_string(), high_water:112.0}));
+        assert_eq!(rebuild(&raised).purchase_history[0].high_water, 112.0);
+    }
+
+    #[test]
+    fn truncated_binary_record_is_an_error(){
+        let path = env::temp_dir().join(format!("stock-sim-journal-{}-truncated.bin", process::id()));
+        let path = path.to_str().unwrap();
+        let mut bytes = encode(&entries()[0], JournalFormat::Binary);
+        bytes.pop();
+        fs::write(path, bytes).unwrap();
+        let read = read(path);
+        fs::remove_file(path).unwrap();
+        assert_eq!(read.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,253 @@
+// Per-hop latency histograms and message rates of this process, fed by the
+// stamped transport. A hop is the queue or fanout exchange a message went through.
+use std::{collections::BTreeMap, fs, io, path::Path, sync::Mutex, time::Instant};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use crate::logging::{self, Component};
+
+// Buckets per power of two, so a percentile is within ~6% of the real value
+const SUB_BUCKETS: u64 = 16;
+
+pub fn now_micros() -> i64{
+    chrono::Utc::now().timestamp_micros()
+}
+
+// Log-linear histogram of microsecond latencies, constant memory however long the run
+#[derive(Clone,Debug,Default)]
+pub struct Histogram{
+    counts: Vec<u64>,
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Histogram{
+    fn bucket(value: u64) -> usize{
+        if value < SUB_BUCKETS{return value as usize;}
+        let exp = 63 - value.leading_zeros() as u64; // >= 4
+        let mantissa = (value >> (exp - 4)) - SUB_BUCKETS;
+        (SUB_BUCKETS + (exp - 4) * SUB_BUCKETS + mantissa) as usize
+    }
+
+    // Middle of the bucket's range
+    fn value(bucket: usize) -> u64{
+        let bucket = bucket as u64;
+        if bucket < SUB_BUCKETS{return bucket;}
+        let shift = (bucket - SUB_BUCKETS) / SUB_BUCKETS;
+        let lower = (SUB_BUCKETS + (bucket - SUB_BUCKETS) % SUB_BUCKETS) << shift;
+        lower + (1 << shift) / 2
+    }
+
+    pub fn record(&mut self, value: u64){
+        let bucket = Histogram::bucket(value);
+        if self.counts.len() <= bucket{self.counts.resize(bucket + 1, 0);}
+        self.counts[bucket]+=1;
+        self.count+=1;
+        self.sum+=value;
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64{
+        self.count
+    }
+
+    pub fn max(&self) -> u64{
+        self.max
+    }
+
+    pub fn mean(&self) -> f64{
+        if self.count == 0{0.0}else{self.sum as f64 / self.count as f64}
+    }
+
+    // Value below which `q` (0..=1) of the samples fall
+    pub fn percentile(&self, q: f64) -> u64{
+        if self.count == 0{return 0;}
+        let rank = ((q * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate(){
+            seen+=count;
+            if seen >= rank{return Histogram::value(bucket).min(self.max);}
+        }
+        self.max
+    }
+}
+
+#[derive(Default)]
+struct HopStats{
+    received: u64,
+    bytes: u64,
+    latency: Histogram, // only stamped messages
+    slowest: Option<(u64, String)>, // latency & correlation ID
+}
+
+#[derive(Default)]
+struct SentStats{
+    sent: u64,
+    bytes: u64,
+}
+
+struct Stats{
+    started: Instant,
+    hops: BTreeMap<String, HopStats>,
+    sent: BTreeMap<String, SentStats>,
+}
+
+lazy_static! {
+    static ref STATS: Mutex<Stats> = Mutex::new(Stats{started:Instant::now(), hops:BTreeMap::new(), sent:BTreeMap::new()});
+}
+
+pub fn record_sent(name: &str, bytes: usize){
+    let mut stats = STATS.lock().unwrap();
+    let sent = stats.sent.entry(name.to_string()).or_default();
+    sent.sent+=1;
+    sent.bytes+=bytes as u64;
+}
+
+// `latency` is None for messages that came without a stamp
+pub fn record_received(hop: &str, bytes: usize, latency: Option<(u64, &str)>){
+    let mut stats = STATS.lock().unwrap();
+    let hop = stats.hops.entry(hop.to_string()).or_default();
+    hop.received+=1;
+    hop.bytes+=bytes as u64;
+    if let Some((micros, correlation_id)) = latency{
+        hop.latency.record(micros);
+        if hop.slowest.as_ref().is_none_or(|(slowest, _)| micros > *slowest){
+            hop.slowest = Some((micros, correlation_id.to_string()));
+        }
+    }
+}
+
+#[derive(Clone,Debug,Serialize)]
+pub struct HopSummary{
+    pub hop:String,
+    pub received:u64,
+    pub bytes:u64,
+    pub rate_per_s:f64,
+    pub p50_us:u64,
+    pub p95_us:u64,
+    pub p99_us:u64,
+    pub mean_us:f64,
+    pub max_us:u64,
+    pub slowest_id:Option<String>, // correlation ID of the slowest message
+}
+
+#[derive(Clone,Debug,Serialize)]
+pub struct SentSummary{
+    pub name:String,
+    pub sent:u64,
+    pub bytes:u64,
+    pub rate_per_s:f64,
+}
+
+#[derive(Clone,Debug,Serialize)]
+pub struct Summary{
+    pub elapsed_s:f64,
+    pub hops:Vec<HopSummary>,
+    pub sent:Vec<SentSummary>,
+}
+
+pub fn summary() -> Summary{
+    let stats = STATS.lock().unwrap();
+    let elapsed_s = stats.started.elapsed().as_secs_f64();
+    let rate = |count: u64| if elapsed_s > 0.0{count as f64 / elapsed_s}else{0.0};
+    let hops = stats.hops.iter().map(|(hop, s)| HopSummary{hop:hop.clone(), received:s.received, bytes:s.bytes, rate_per_s:rate(s.received),
+        p50_us:s.latency.percentile(0.5), p95_us:s.latency.percentile(0.95), p99_us:s.latency.percentile(0.99),
+        mean_us:s.latency.mean(), max_us:s.latency.max(), slowest_id:s.slowest.as_ref().map(|(_, id)| id.clone())}).collect();
+    let sent = stats.sent.iter().map(|(name, s)| SentSummary{name:name.clone(), sent:s.sent, bytes:s.bytes, rate_per_s:rate(s.sent)}).collect();
+    Summary{elapsed_s, hops, sent}
+}
+
+// A table for human output, one record per hop with the numbers as fields otherwise
+pub fn print_summary(component: Component){
+    let summary = summary();
+    logging::info(component, format!("Message latency per hop over {:.1}s (µs)", summary.elapsed_s)).field("elapsed_s", summary.elapsed_s).emit();
+    logging::info(component, "latency table").display(format!("  {:<20} {:>7} {:>8} {:>8} {:>8} {:>8} {:>8}", "hop", "msgs", "rate/s", "p50", "p95", "p99", "max"))
+        .field("header", true).emit();
+    for h in summary.hops.iter(){
+        logging::info(component, format!("hop {}", h.hop))
+            .display(format!("  {:<20} {:>7} {:>8.2} {:>8} {:>8} {:>8} {:>8}", h.hop, h.received, h.rate_per_s, h.p50_us, h.p95_us, h.p99_us, h.max_us))
+            .field("latency", h).emit();
+    }
+    logging::info(component, "Messages sent").emit();
+    for s in summary.sent.iter(){
+        logging::info(component, format!("sent {}", s.name)).display(format!("  {:<20} {:>7} {:>8.2}/s", s.name, s.sent, s.rate_per_s))
+            .field("sent", s).emit();
+    }
+}
+
+// Write the summary as CSV for a `.csv` path, JSON otherwise
+pub fn export(path: &str) -> io::Result<()>{
+    let summary = summary();
+    let contents = if Path::new(path).extension().and_then(|ext| ext.to_str()) == Some("csv"){
+        let mut csv = String::from("kind,name,count,bytes,rate_per_s,p50_us,p95_us,p99_us,mean_us,max_us,slowest_id\n");
+        for h in summary.hops.iter(){
+            csv.push_str(&format!("received,{},{},{},{:.3},{},{},{},{:.1},{},{}\n", h.hop, h.received, h.bytes, h.rate_per_s,
+                h.p50_us, h.p95_us, h.p99_us, h.mean_us, h.max_us, h.slowest_id.clone().unwrap_or_default()));
+        }
+        for s in summary.sent.iter(){
+            csv.push_str(&format!("sent,{},{},{},{:.3},,,,,,\n", s.name, s.sent, s.bytes, s.rate_per_s));
+        }
+        csv
+    }else{
+        serde_json::to_string_pretty(&summary).expect("Failed to serialize")
+    };
+    fs::write(path, contents)
+}
+
+// Print the summary at shutdown, and export it when the binary got `--stats-out <path>`
+pub fn finish(component: Component, path: Option<&str>){
+    print_summary(component);
+    if let Some(path) = path{
+        match export(path){
+            Ok(()) => logging::info(component, format!("Latency summary written to {}", path)).field("path", path).emit(),
+            Err(err) => logging::error(component, format!("failed to write {}: {}", path, err)).field("path", path).emit(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn small_values_get_a_bucket_each(){
+        for value in 0..SUB_BUCKETS{
+            assert_eq!(Histogram::value(Histogram::bucket(value)), value);
+        }
+        // from 16 on a bucket is as wide as a 16th of its power of two
+        assert_eq!(Histogram::bucket(16), 16);
+        assert_eq!(Histogram::bucket(31), 31);
+        assert_eq!(Histogram::bucket(32), Histogram::bucket(33));
+        assert_eq!(Histogram::bucket(34), 33);
+        assert_eq!(Histogram::value(32), 33);
+    }
+
+    #[test]
+    fn buckets_stay_within_a_32nd_of_the_value(){
+        for value in (SUB_BUCKETS..1_000_000).step_by(7){
+            let estimate = Histogram::value(Histogram::bucket(value));
+            assert!(estimate.abs_diff(value) <= value / 32, "{} estimated as {}", value, estimate);
+        }
+    }
+
+    #[test]
+    fn percentiles_of_known_samples(){
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile(0.5), 0);
+        for value in [5, 5, 5, 7]{histogram.record(value);}
+        assert_eq!(histogram.percentile(0.5), 5);
+        assert_eq!(histogram.percentile(0.75), 5);
+        assert_eq!(histogram.percentile(0.99), 7);
+        assert_eq!(histogram.mean(), 5.5);
+
+        let mut histogram = Histogram::default();
+        for value in 1..=100{histogram.record(value);}
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.percentile(0.0), 1);
+        assert!(histogram.percentile(0.5).abs_diff(50) <= 50 / 32);
+        assert!(histogram.percentile(0.95).abs_diff(95) <= 95 / 32);
+        // never past the largest sample, even though its bucket's middle is
+        assert_eq!(histogram.percentile(1.0), 100);
+        assert_eq!(histogram.max(), 100);
+    }
+}
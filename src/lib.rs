@@ -0,0 +1,37 @@
+// Shared library for the stock simulation.
+// The exchange (stock.rs) and the brokers (broker.rs) all talk
+// through these types, so any schema change only has to be made here.
+// The exchange, user and broker loops live here too, so they can be driven
+// over any `Transport`, including a single in-process one.
+pub mod broker;
+pub mod candles;
+pub mod config;
+pub mod exchange;
+pub mod journal;
+pub mod latency;
+pub mod logging;
+pub mod market_data;
+pub mod metrics;
+pub mod message;
+pub mod news;
+pub mod order;
+pub mod orderbook;
+pub mod portfolio;
+pub mod price_model;
+pub mod purchase;
+pub mod replay;
+pub mod risk;
+pub mod rng;
+pub mod router;
+pub mod session;
+pub mod strategy;
+pub mod transport;
+pub mod users;
+
+pub use message::{Order, Stock};
+pub use purchase::{iterate_stock_list, PurchaseDetails};
+
+// Colour reformating
+pub const ANSI_BOLD_GREEN: &str = "\x1b[1;32m"; // Bold green color
+pub const ANSI_RESET: &str = "\x1b[0m"; // Reset color and style
+pub const ANSI_BOLD_RED: &str = "\x1b[1;31m"; // Bold red color
@@ -0,0 +1,286 @@
+// Levelled, structured logging for every component. A record carries the component
+// it came from, a message and fields such as symbol, user ID and price. It is rendered
+// in the original colourised format (human), the same without colours (plain) or as
+// one JSON object per line, and filtered per component, e.g. "warn,broker=info,user3=debug".
+use std::{fmt, sync::RwLock};
+use chrono::Local;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use crate::{ANSI_BOLD_GREEN, ANSI_BOLD_RED, ANSI_RESET};
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq,PartialOrd,Ord,Serialize,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level{
+    Debug,
+    Info,
+    Warn,
+    Error,
+    Off, // only as a threshold
+}
+
+impl Level{
+    pub fn parse(s: &str) -> Result<Level, String>{
+        match s.trim().to_ascii_lowercase().as_str(){
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            "off" => Ok(Level::Off),
+            other => Err(format!("unknown log level {:?}, expected debug, info, warn, error or off", other)),
+        }
+    }
+}
+
+impl fmt::Display for Level{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        let name = match self{Level::Debug => "debug", Level::Info => "info", Level::Warn => "warn", Level::Error => "error", Level::Off => "off"};
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone,Copy,Debug,Default,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat{
+    #[default]
+    Human, // the original colourised lines
+    Plain, // human without the ANSI colours
+    Json,
+}
+
+impl LogFormat{
+    pub fn parse(s: &str) -> Result<LogFormat, String>{
+        match s.trim().to_ascii_lowercase().as_str(){
+            "human" => Ok(LogFormat::Human),
+            "plain" => Ok(LogFormat::Plain),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format {:?}, expected human, plain or json", other)),
+        }
+    }
+}
+
+// `[log]` in the config file, `--log-format`, `--log-level` & `--log-filter` override it
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig{
+    pub format: LogFormat,
+    pub level: Level, // for components without a filter of their own
+    pub filter: String, // "<component>=<level>,..." with components exchange, users, user<n>, broker, broker<n>, ...
+}
+
+impl Default for LogConfig{
+    fn default() -> Self {
+        LogConfig{format:LogFormat::Human, level:Level::Debug, filter:String::new()}
+    }
+}
+
+impl LogConfig{
+    pub fn validate(&self) -> Result<(), String>{
+        parse_filter(&self.filter).map(|_| ())
+    }
+}
+
+// (component, level) pairs
+type Targets = Vec<(String, Level)>;
+
+// A bare level in the list sets the default
+fn parse_filter(spec: &str) -> Result<(Option<Level>, Targets), String>{
+    let mut default = None;
+    let mut targets = Vec::new();
+    for item in spec.split(',').map(str::trim).filter(|item| !item.is_empty()){
+        match item.split_once('='){
+            Some((target, level)) => {
+                let target = target.trim().to_ascii_lowercase().replace(' ', "");
+                if target.is_empty(){return Err(format!("missing component in {:?}", item));}
+                targets.push((target, Level::parse(level)?));
+            }
+            None => default = Some(Level::parse(item)?),
+        }
+    }
+    Ok((default, targets))
+}
+
+// Who a record is about
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Component{
+    Exchange,
+    Users, // the users thread as a whole
+    User(i8),
+    Broker(i8),
+    Named(&'static str), // the binaries and helpers: Simulation, Journal, Replay...
+}
+
+impl Component{
+    // Filter names, the most specific first
+    fn targets(&self) -> (Option<String>, String){
+        match self{
+            Component::Exchange => (None, "exchange".to_string()),
+            Component::Users => (None, "users".to_string()),
+            Component::User(id) => (Some(format!("user{}", id)), "users".to_string()),
+            Component::Broker(no) => (Some(format!("broker{}", no)), "broker".to_string()),
+            Component::Named(name) => (None, name.to_ascii_lowercase()),
+        }
+    }
+
+    // Prefix of the human lines
+    fn prefix(&self) -> String{
+        match self{
+            Component::Exchange => "Exchange".to_string(),
+            Component::Users => "Users".to_string(),
+            Component::User(id) => format!("User{}", id),
+            Component::Broker(no) => format!("Broker {}", no),
+            Component::Named(name) => name.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Component{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            Component::User(id) => write!(f, "user {}", id),
+            Component::Broker(no) => write!(f, "broker {}", no),
+            other => write!(f, "{}", other.targets().1),
+        }
+    }
+}
+
+// Colour of a whole human line
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Tone{
+    Plain,
+    Good, // bold green
+    Bad, // bold red
+}
+
+struct Settings{
+    format: LogFormat,
+    level: Level,
+    targets: Targets,
+}
+
+lazy_static! {
+    static ref SETTINGS: RwLock<Settings> = RwLock::new(Settings{format:LogFormat::Human, level:Level::Debug, targets:Vec::new()});
+}
+
+pub fn init(config: &LogConfig) -> Result<(), String>{
+    let (default, targets) = parse_filter(&config.filter)?;
+    *SETTINGS.write().unwrap() = Settings{format:config.format, level:default.unwrap_or(config.level), targets};
+    Ok(())
+}
+
+pub fn enabled(level: Level, component: Component) -> bool{
+    let settings = SETTINGS.read().unwrap();
+    let (specific, kind) = component.targets();
+    let threshold = specific.and_then(|s| settings.targets.iter().rev().find(|(t, _)| *t == s))
+        .or_else(|| settings.targets.iter().rev().find(|(t, _)| *t == kind))
+        .map(|(_, level)| *level).unwrap_or(settings.level);
+    level != Level::Off && level >= threshold
+}
+
+// Whether `--log-filter` names the component, for records only wanted on request
+pub fn filtered(component: Component) -> bool{
+    let settings = SETTINGS.read().unwrap();
+    let (specific, kind) = component.targets();
+    settings.targets.iter().any(|(t, _)| *t == kind || Some(t) == specific.as_ref())
+}
+
+// Drop the ANSI colour codes some messages carry inline
+pub fn strip_ansi(text: &str) -> String{
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next(){
+        if c == '\x1b'{
+            // skip up to the final letter of the escape sequence
+            for c in chars.by_ref(){
+                if c.is_ascii_alphabetic(){break;}
+            }
+        }else{
+            out.push(c);
+        }
+    }
+    out
+}
+
+// One log record, built up and then emitted
+#[must_use = "a record does nothing until it is emitted"]
+pub struct Record{
+    level: Level,
+    component: Component,
+    message: String,
+    fields: Map<String, Value>,
+    tone: Option<Tone>,
+    timed: bool,
+    display: Option<String>,
+}
+
+pub fn debug(component: Component, message: impl Into<String>) -> Record{Record::new(Level::Debug, component, message)}
+pub fn info(component: Component, message: impl Into<String>) -> Record{Record::new(Level::Info, component, message)}
+pub fn warn(component: Component, message: impl Into<String>) -> Record{Record::new(Level::Warn, component, message)}
+pub fn error(component: Component, message: impl Into<String>) -> Record{Record::new(Level::Error, component, message)}
+
+impl Record{
+    fn new(level: Level, component: Component, message: impl Into<String>) -> Record{
+        Record{level, component, message:message.into(), fields:Map::new(), tone:None, timed:false, display:None}
+    }
+
+    pub fn field(mut self, name: &str, value: impl Serialize) -> Record{
+        self.fields.insert(name.to_string(), serde_json::to_value(value).unwrap_or(Value::Null));
+        self
+    }
+
+    pub fn symbol(self, symbol: &str) -> Record{self.field("symbol", symbol)}
+    pub fn user(self, user_id: i8) -> Record{self.field("user_id", user_id)}
+    pub fn price(self, price: f64) -> Record{self.field("price", price)}
+
+    pub fn tone(mut self, tone: Tone) -> Record{
+        self.tone = Some(tone);
+        self
+    }
+
+    // Human lines start with "Time: <local time>"
+    pub fn timed(mut self) -> Record{
+        self.timed = true;
+        self
+    }
+
+    // Human rendering of its own, for multi-line layouts like the news box
+    pub fn display(mut self, text: String) -> Record{
+        self.display = Some(text);
+        self
+    }
+
+    pub fn emit(self){
+        if !enabled(self.level, self.component){return;}
+        let format = SETTINGS.read().unwrap().format;
+        let line = match format{
+            LogFormat::Human => self.human(),
+            LogFormat::Plain => strip_ansi(&self.human()),
+            LogFormat::Json => self.json(),
+        };
+        if self.level >= Level::Error{eprintln!("{}", line);}else{println!("{}", line);}
+    }
+
+    fn human(&self) -> String{
+        if let Some(display) = &self.display{return display.clone();}
+        let time = if self.timed{format!("Time: {} ", Local::now().format("%Y-%m-%d %H:%M:%S"))}else{String::new()};
+        let line = format!("{}{}: {}", time, self.component.prefix(), self.message);
+        let tone = self.tone.unwrap_or(if self.level >= Level::Warn{Tone::Bad}else{Tone::Plain});
+        match tone{
+            Tone::Plain => line,
+            Tone::Good => format!("{}{}{}", ANSI_BOLD_GREEN, line, ANSI_RESET),
+            Tone::Bad => format!("{}{}{}", ANSI_BOLD_RED, line, ANSI_RESET),
+        }
+    }
+
+    fn json(&self) -> String{
+        let mut object = Map::new();
+        object.insert("ts".to_string(), Value::String(Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false)));
+        object.insert("level".to_string(), Value::String(self.level.to_string()));
+        object.insert("component".to_string(), Value::String(self.component.to_string()));
+        object.insert("msg".to_string(), Value::String(strip_ansi(&self.message)));
+        for (name, value) in self.fields.iter(){
+            object.insert(name.clone(), value.clone());
+        }
+        Value::Object(object).to_string()
+    }
+}
@@ -0,0 +1,151 @@
+// Market data protocol: a full snapshot, then numbered per-symbol updates for
+// the prices that moved, and a heartbeat each round carrying the last number.
+// The exchange publishes through `Feed`, each broker keeps a `Book` that detects
+// gaps and asks for a new snapshot until it's consistent again.
+use crate::message::MarketData;
+use crate::order::now_millis;
+use crate::Stock;
+
+// Exchange side, numbers the updates
+#[derive(Default)]
+pub struct Feed{
+    seq: u64, // last update sent
+}
+
+impl Feed{
+    pub fn snapshot(&self, stocks: &[Stock]) -> MarketData{
+        MarketData::Snapshot{seq:self.seq, stocks:stocks.to_vec(), timestamp:now_millis()}
+    }
+
+    pub fn update(&mut self, stock_name: &str, price: f64) -> MarketData{
+        self.seq+=1;
+        MarketData::Update{seq:self.seq, stock_name:stock_name.to_string(), price, timestamp:now_millis()}
+    }
+
+    pub fn heartbeat(&self) -> MarketData{
+        MarketData::Heartbeat{seq:self.seq, timestamp:now_millis()}
+    }
+}
+
+// What applying a message did to a book
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Applied{
+    Applied,
+    Old, // at or before the book's sequence, ignored
+    Gap{last:Option<u64>, got:u64}, // updates were missed, the book needs a snapshot
+}
+
+// Broker side, the stock list as the updates left it
+#[derive(Clone,Debug,Default)]
+pub struct Book{
+    seq: Option<u64>, // None until the first snapshot
+    stocks: Vec<Stock>,
+    stale: bool, // missed updates since the last snapshot
+}
+
+impl Book{
+    pub fn stocks(&self) -> &[Stock]{
+        &self.stocks
+    }
+
+    pub fn seq(&self) -> Option<u64>{
+        self.seq
+    }
+
+    pub fn needs_snapshot(&self) -> bool{
+        self.seq.is_none() || self.stale
+    }
+
+    fn set_price(&mut self, stock_name: &str, price: f64){
+        match self.stocks.iter_mut().find(|s| s.name == stock_name){
+            Some(stock) => stock.value = price,
+            None => self.stocks.push(Stock{name:stock_name.to_string(), value:price}),
+        }
+    }
+
+    pub fn apply(&mut self, message: &MarketData) -> Applied{
+        let next = self.seq.map(|seq| seq + 1);
+        match message{
+            MarketData::Snapshot{seq, stocks, ..} => {
+                // one from before updates already applied would roll their prices back
+                if self.seq.is_some_and(|last| *seq < last){return Applied::Old;}
+                self.stocks = stocks.clone();
+                self.seq = Some(*seq);
+                self.stale = false;
+                Applied::Applied
+            }
+            MarketData::Update{seq, stock_name, price, ..} => {
+                if self.seq.is_some_and(|last| *seq <= last){return Applied::Old;}
+                // prices are absolute, so a late update is still the latest for its symbol
+                self.set_price(stock_name, *price);
+                let last = self.seq.replace(*seq);
+                if next == Some(*seq){return Applied::Applied;}
+                self.stale = true;
+                Applied::Gap{last, got:*seq}
+            }
+            MarketData::Heartbeat{seq, ..} => {
+                if self.seq.is_some_and(|last| *seq <= last){
+                    return if self.seq == Some(*seq){Applied::Applied}else{Applied::Old};
+                }
+                let last = self.seq.replace(*seq);
+                self.stale = true;
+                Applied::Gap{last, got:*seq}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn stock(name: &str, value: f64) -> Stock{
+        Stock{name:name.to_string(), value}
+    }
+
+    fn update(seq: u64, price: f64) -> MarketData{
+        MarketData::Update{seq, stock_name:"AAPL".to_string(), price, timestamp:0}
+    }
+
+    #[test]
+    fn updates_in_sequence_apply(){
+        let mut book = Book::default();
+        assert!(book.needs_snapshot());
+        assert_eq!(book.apply(&MarketData::Snapshot{seq:3, stocks:vec![stock("AAPL", 100.0)], timestamp:0}), Applied::Applied);
+        assert_eq!(book.apply(&update(4, 101.0)), Applied::Applied);
+        assert_eq!(book.apply(&MarketData::Heartbeat{seq:4, timestamp:0}), Applied::Applied);
+        assert_eq!(book.apply(&update(4, 99.0)), Applied::Old);
+        assert_eq!((book.seq(), book.stocks()[0].value, book.needs_snapshot()), (Some(4), 101.0, false));
+    }
+
+    #[test]
+    fn missed_updates_are_a_gap_until_the_next_snapshot(){
+        let mut book = Book::default();
+        book.apply(&MarketData::Snapshot{seq:1, stocks:vec![stock("AAPL", 100.0)], timestamp:0});
+        assert_eq!(book.apply(&update(3, 102.0)), Applied::Gap{last:Some(1), got:3});
+        // the price is still the latest for its symbol
+        assert_eq!(book.stocks()[0].value, 102.0);
+        assert!(book.needs_snapshot());
+        assert_eq!(book.apply(&MarketData::Snapshot{seq:2, stocks:vec![stock("AAPL", 101.0)], timestamp:0}), Applied::Old);
+        assert_eq!(book.apply(&MarketData::Snapshot{seq:3, stocks:vec![stock("AAPL", 102.0)], timestamp:0}), Applied::Applied);
+        assert!(!book.needs_snapshot());
+    }
+
+    #[test]
+    fn heartbeat_ahead_of_the_book_is_a_gap(){
+        let mut book = Book::default();
+        assert_eq!(book.apply(&update(1, 100.0)), Applied::Gap{last:None, got:1});
+        book.apply(&MarketData::Snapshot{seq:1, stocks:vec![stock("AAPL", 100.0)], timestamp:0});
+        assert_eq!(book.apply(&MarketData::Heartbeat{seq:5, timestamp:0}), Applied::Gap{last:Some(1), got:5});
+        assert!(book.needs_snapshot());
+    }
+
+    #[test]
+    fn feed_numbers_its_updates(){
+        let mut feed = Feed::default();
+        assert_eq!(feed.update("AAPL", 1.0).seq(), 1);
+        assert_eq!(feed.update("MSFT", 2.0).seq(), 2);
+        assert_eq!(feed.heartbeat().seq(), 2);
+        assert_eq!(feed.snapshot(&[]).seq(), 2);
+    }
+}
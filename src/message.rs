@@ -0,0 +1,340 @@
+use std::fmt;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use crate::logging::{self, Component};
+use crate::portfolio::PortfolioSnapshot;
+use crate::purchase::{PurchaseDetails, TrailingStop};
+use crate::router::BrokerTerms;
+
+// Parse a message off the network. One that doesn't parse is logged and skipped,
+// a bad message mustn't take the thread down
+pub fn decode<T: DeserializeOwned>(component: Component, body: &[u8]) -> Option<T>{
+    decode_with(component, body, |body| serde_json::from_slice(body))
+}
+
+pub fn decode_with<T>(component: Component, body: &[u8], parse: fn(&[u8]) -> serde_json::Result<T>) -> Option<T>{
+    parse(body).map_err(|err| {
+        logging::warn(component, format!("skipped a message that doesn't parse: {}", err))
+            .field("error", err.to_string()).field("body", String::from_utf8_lossy(body)).emit();
+    }).ok()
+}
+
+// Stock price published by the exchange
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct Stock{
+    pub name:String,
+    pub value:f64,
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Serialize,Deserialize)]
+pub enum Side{
+    Buy,
+    Sell,
+}
+
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderType{
+    Market, // takes whatever the book offers, what can't fill at once is cancelled
+    #[default]
+    Limit, // at bid_price or better
+    Stop, // a market order once the price reaches stop_price
+    StopLimit, // a limit order at bid_price once the price reaches stop_price
+}
+
+impl OrderType{
+    // "market", "limit", "stop" or "stop_limit"
+    pub fn parse(spec: &str) -> Result<OrderType, String>{
+        match spec.trim().replace('-', "_").as_str(){
+            "market" => Ok(OrderType::Market),
+            "limit" => Ok(OrderType::Limit),
+            "stop" => Ok(OrderType::Stop),
+            "stop_limit" => Ok(OrderType::StopLimit),
+            other => Err(format!("unknown order type '{}', expected market, limit, stop or stop_limit", other)),
+        }
+    }
+
+    pub fn is_stop(&self) -> bool{
+        matches!(self, OrderType::Stop | OrderType::StopLimit)
+    }
+
+    // What the broker sends the exchange once a stop is triggered
+    pub fn on_trigger(&self) -> OrderType{
+        match self{
+            OrderType::Stop => OrderType::Market,
+            OrderType::StopLimit => OrderType::Limit,
+            other => *other,
+        }
+    }
+}
+
+impl fmt::Display for OrderType{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            OrderType::Market => write!(f, "market"),
+            OrderType::Limit => write!(f, "limit"),
+            OrderType::Stop => write!(f, "stop"),
+            OrderType::StopLimit => write!(f, "stop_limit"),
+        }
+    }
+}
+
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeInForce{
+    #[default]
+    Gtc, // until filled or the session ends
+    Day, // cancelled at the close of the exchange's trading day
+    Ioc, // what doesn't fill at once is cancelled
+    Fok, // fills completely at once or not at all
+}
+
+impl TimeInForce{
+    // "gtc", "day", "ioc" or "fok"
+    pub fn parse(spec: &str) -> Result<TimeInForce, String>{
+        match spec.trim().to_lowercase().as_str(){
+            "gtc" => Ok(TimeInForce::Gtc),
+            "day" => Ok(TimeInForce::Day),
+            "ioc" => Ok(TimeInForce::Ioc),
+            "fok" => Ok(TimeInForce::Fok),
+            other => Err(format!("unknown time in force '{}', expected gtc, day, ioc or fok", other)),
+        }
+    }
+}
+
+impl fmt::Display for TimeInForce{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            TimeInForce::Gtc => write!(f, "GTC"),
+            TimeInForce::Day => write!(f, "DAY"),
+            TimeInForce::Ioc => write!(f, "IOC"),
+            TimeInForce::Fok => write!(f, "FOK"),
+        }
+    }
+}
+
+// User's order sent to the brokers
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct Order{
+    pub order_id:String, // unique for the whole run
+    pub client_id:i8, // user who placed it
+    pub stock_name: String,
+    pub side:Side,
+    pub bid_price:f64,
+    pub take_profit:f64,
+    pub cut_loss:f64,
+    pub num_stock:i128,
+    pub timestamp:i64, // ms since epoch
+    pub reply_to:String, // queue the execution reports go back to
+    #[serde(default)]
+    pub short:bool, // sell of borrowed shares
+    #[serde(default)]
+    pub trailing_stop:Option<TrailingStop>, // for the holding a buy leaves
+    #[serde(default)]
+    pub expire_after_ms:Option<i64>, // the holding is sold this long after the fill, None keeps it until a level triggers
+    #[serde(default)]
+    pub order_type:OrderType,
+    #[serde(default)]
+    pub time_in_force:TimeInForce,
+    #[serde(default)]
+    pub stop_price:Option<f64>, // for stop & stop-limit orders
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub enum OrderStatus{
+    New,
+    Accepted,
+    PartiallyFilled,
+    Filled,
+    Rejected{reason:String},
+    Cancelled,
+    Replaced{by:String}, // cancelled for the replacement order `by`
+    CancelRejected{reason:String}, // a cancel or replace that couldn't be honoured, the order carries on
+    LevelsAmended, // for an amend request, the holding has its new levels
+    AmendRejected{reason:String},
+}
+
+// User's request about an order already sent, or a holding the broker watches,
+// on the same queue as the orders
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub enum OrderControl{
+    Cancel{order_id:String, client_id:i8, stock_name:String, side:Side, reply_to:String},
+    // cancel what's left of order_id and send `order` in its place
+    Replace{order_id:String, order:Order},
+    // new protective levels for the user's holding, None keeps a level
+    AmendLevels{request_id:String, client_id:i8, stock_name:String, take_profit:Option<f64>, cut_loss:Option<f64>, reply_to:String},
+}
+
+// What arrives on a broker's order queue
+#[derive(Clone,Debug,Serialize)]
+#[serde(untagged)]
+pub enum UserRequest{
+    Order(Order),
+    Control(OrderControl),
+}
+
+impl UserRequest{
+    // serde's untagged enums can't buffer the i128 quantities, so each form is tried in turn
+    pub fn from_slice(body: &[u8]) -> serde_json::Result<UserRequest>{
+        serde_json::from_slice(body).map(UserRequest::Control).or_else(|_| serde_json::from_slice(body).map(UserRequest::Order))
+    }
+}
+
+// Broker's report on one of the user's orders, sent on every status change.
+// Replies to an amend request carry its request_id as the order_id
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct ExecutionReport{
+    pub order_id:String,
+    pub client_id:i8,
+    pub broker_no:i8,
+    pub stock_name:String,
+    pub side:Side,
+    pub status:OrderStatus,
+    pub last_qty:i128, // filled by this report
+    pub last_price:f64,
+    pub cum_qty:i128,
+    pub leaves_qty:i128,
+    pub timestamp:i64,
+    #[serde(default)]
+    pub fee:f64, // broker's commission & markup on last_qty
+}
+
+// Limit order forwarded by a broker to the exchange's order book
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct LimitOrder{
+    pub order_id:String,
+    pub broker_no:i8,
+    pub user_id:i8,
+    pub stock_name:String,
+    pub side:Side,
+    pub price:f64, // ignored by market orders
+    pub num_stock:i128,
+    #[serde(default)]
+    pub order_type:OrderType, // market or limit, brokers hold stops until they trigger
+    #[serde(default)]
+    pub time_in_force:TimeInForce,
+}
+
+impl LimitOrder{
+    // Whether what doesn't fill at once rests on the book
+    pub fn rests(&self) -> bool{
+        self.order_type != OrderType::Market && matches!(self.time_in_force, TimeInForce::Gtc | TimeInForce::Day)
+    }
+}
+
+// Broker asking the exchange to take a resting order off the book
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct CancelOrder{
+    pub order_id:String,
+    pub broker_no:i8,
+    pub user_id:i8,
+    pub stock_name:String,
+}
+
+// What arrives on the exchange's order queue
+#[derive(Clone,Debug,Serialize)]
+#[serde(untagged)]
+pub enum ExchangeRequest{
+    Order(LimitOrder),
+    Cancel(CancelOrder),
+}
+
+impl ExchangeRequest{
+    // an order has every field of a cancel, so it's tried first
+    pub fn from_slice(body: &[u8]) -> serde_json::Result<ExchangeRequest>{
+        serde_json::from_slice(body).map(ExchangeRequest::Order).or_else(|_| serde_json::from_slice(body).map(ExchangeRequest::Cancel))
+    }
+}
+
+// The exchange telling the brokers about orders it took off the book, on the order notices fanout
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub enum BookNotice{
+    // what was left of an order is cancelled: market, IOC & FOK remainders and DAY orders at the close
+    Cancelled{order_id:String, broker_no:i8, user_id:i8, stock_name:String, filled_qty:i128, cancelled_qty:i128, reason:String},
+    DayClosed{day:u32},
+    // a cancel for an order that isn't on the book anymore, or never rested there
+    CancelRejected{order_id:String, broker_no:i8, reason:String},
+}
+
+// Fill published by the exchange back to the broker that owns the order
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct Execution{
+    pub order_id:String,
+    pub broker_no:i8,
+    pub user_id:i8,
+    pub stock_name:String,
+    pub side:Side,
+    pub price:f64,
+    pub num_stock:i128, // filled in this execution
+    pub leaves_qty:i128, // still resting on the book
+    pub aggressor:bool, // this order took liquidity
+}
+
+// Brokers announce themselves to the exchange when they start and stop
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub enum BrokerControl{
+    Register{broker_no:i8},
+    Deregister{broker_no:i8},
+}
+
+// Broadcast by each broker so the users' router knows its terms and load
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct BrokerStatus{
+    pub broker_no:i8,
+    pub terms:BrokerTerms,
+    pub outstanding:usize, // orders still working
+    pub active:bool, // false once the broker stopped
+}
+
+// Broadcast by the exchange when it closes, brokers then flush and shut down
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct EndOfSession{
+    pub reason:String,
+    pub timestamp:i64,
+}
+
+// Broker's last word before deregistering
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct FinalReport{
+    pub broker_no:i8,
+    pub cancelled:Vec<String>, // orders still working when the session ended
+    pub open_positions:Vec<PurchaseDetails>, // holdings still under cut loss & take profit watch
+    pub portfolios:Vec<PortfolioSnapshot>,
+}
+
+// Market data from the exchange. Snapshots & heartbeats go out on the stock info
+// fanout, updates on the stock trending one, numbered in one sequence so brokers
+// can tell when they missed one.
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub enum MarketData{
+    Snapshot{seq:u64, stocks:Vec<Stock>, timestamp:i64}, // every price as of update `seq`
+    Update{seq:u64, stock_name:String, price:f64, timestamp:i64}, // one symbol's new price
+    Heartbeat{seq:u64, timestamp:i64}, // once a round, the last update sent so far
+}
+
+impl MarketData{
+    pub fn seq(&self) -> u64{
+        match self{
+            MarketData::Snapshot{seq, ..} | MarketData::Update{seq, ..} | MarketData::Heartbeat{seq, ..} => *seq,
+        }
+    }
+}
+
+// Broker asking the exchange for a fresh snapshot
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct SnapshotRequest{
+    pub broker_no:i8,
+    pub last_seq:Option<u64>, // last update it applied, None before its first snapshot
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn bad_messages_are_skipped(){
+        assert!(decode::<EndOfSession>(Component::Exchange, b"not json").is_none());
+        assert!(decode_with(Component::Exchange, br#"{"broker_no":1}"#, ExchangeRequest::from_slice).is_none());
+        let end: Option<EndOfSession> = decode(Component::Exchange, br#"{"reason":"done","timestamp":1}"#);
+        assert_eq!(end.map(|end| end.reason), Some("done".to_string()));
+    }
+}
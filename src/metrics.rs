@@ -0,0 +1,120 @@
+// Counters & gauges of this process in the Prometheus text format, served over
+// plain HTTP with `--metrics <addr>` so a local Prometheus can scrape a long run.
+use std::{collections::BTreeMap, io::{self, BufRead, BufReader, Write}, net::{TcpListener, TcpStream}, sync::Mutex, thread};
+use lazy_static::lazy_static;
+use crate::logging::{self, Component};
+use crate::PurchaseDetails;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Kind{
+    Counter,
+    Gauge,
+}
+
+pub struct Metric{
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+}
+
+pub const ORDERS_RECEIVED: Metric = Metric{name:"stock_sim_orders_received_total", help:"Orders received, by the exchange from brokers and by each broker from users", kind:Kind::Counter};
+pub const FILLS: Metric = Metric{name:"stock_sim_fills_total", help:"Fills, published by the exchange or applied by a broker to its own orders", kind:Kind::Counter};
+pub const REJECTIONS: Metric = Metric{name:"stock_sim_rejections_total", help:"Orders rejected or ignored, by reason", kind:Kind::Counter};
+pub const PRICE: Metric = Metric{name:"stock_sim_price", help:"Current price per symbol from the stock list", kind:Kind::Gauge};
+pub const MARKET_DATA_GAPS: Metric = Metric{name:"stock_sim_market_data_gaps_total", help:"Market data updates a broker found missing, each followed by a snapshot request", kind:Kind::Counter};
+pub const RECEIVE_TIMEOUTS: Metric = Metric{name:"stock_sim_queue_receive_timeouts_total", help:"Receives that timed out without a message, by queue or fanout exchange", kind:Kind::Counter};
+// each user's portfolio as the broker sees it, by broker and user
+pub const CASH: Metric = Metric{name:"stock_sim_portfolio_cash", help:"User's cash, by broker and user", kind:Kind::Gauge};
+pub const RESERVED_CASH: Metric = Metric{name:"stock_sim_portfolio_reserved_cash", help:"Cash held for a user's working buy orders, by broker and user", kind:Kind::Gauge};
+pub const EQUITY: Metric = Metric{name:"stock_sim_portfolio_equity", help:"User's cash plus the market value of their positions, by broker and user", kind:Kind::Gauge};
+pub const REALISED_PNL: Metric = Metric{name:"stock_sim_portfolio_realised_pnl", help:"User's realised P&L, by broker and user", kind:Kind::Gauge};
+pub const UNREALISED_PNL: Metric = Metric{name:"stock_sim_portfolio_unrealised_pnl", help:"User's unrealised P&L marked to the stock list, by broker and user", kind:Kind::Gauge};
+// collected from PURCHASE_HISTORY at scrape time
+pub const OPEN_POSITIONS: Metric = Metric{name:"stock_sim_open_positions", help:"Holdings under cut loss & take profit watch, by broker", kind:Kind::Gauge};
+pub const OPEN_SHARES: Metric = Metric{name:"stock_sim_open_position_shares", help:"Shares held under cut loss & take profit watch, by broker and symbol", kind:Kind::Gauge};
+
+const ALL: [&Metric; 13] = [&ORDERS_RECEIVED, &FILLS, &REJECTIONS, &PRICE, &MARKET_DATA_GAPS, &RECEIVE_TIMEOUTS,
+    &CASH, &RESERVED_CASH, &EQUITY, &REALISED_PNL, &UNREALISED_PNL, &OPEN_POSITIONS, &OPEN_SHARES];
+
+type Labels = Vec<(String, String)>;
+
+lazy_static! {
+    // value of every series, by metric name then labels
+    static ref SERIES: Mutex<BTreeMap<&'static str, BTreeMap<Labels, f64>>> = Mutex::new(BTreeMap::new());
+}
+
+fn labels(pairs: &[(&str, &str)]) -> Labels{
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+pub fn inc(metric: &Metric, pairs: &[(&str, &str)]){
+    *SERIES.lock().unwrap().entry(metric.name).or_default().entry(labels(pairs)).or_insert(0.0)+=1.0;
+}
+
+pub fn set(metric: &Metric, pairs: &[(&str, &str)], value: f64){
+    SERIES.lock().unwrap().entry(metric.name).or_default().insert(labels(pairs), value);
+}
+
+fn escape(value: &str) -> String{
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_series(out: &mut String, name: &str, labels: &Labels, value: f64){
+    out.push_str(name);
+    if !labels.is_empty(){
+        let pairs: Vec<String> = labels.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+        out.push_str(&format!("{{{}}}", pairs.join(",")));
+    }
+    out.push_str(&format!(" {}\n", value));
+}
+
+// Open positions per broker & symbol, straight from the purchase records
+fn collect_positions() -> BTreeMap<&'static str, BTreeMap<Labels, f64>>{
+    let mut collected: BTreeMap<&'static str, BTreeMap<Labels, f64>> = BTreeMap::new();
+    for d in PurchaseDetails::open_positions(){
+        let broker = d.broker_no.to_string();
+        *collected.entry(OPEN_POSITIONS.name).or_default().entry(labels(&[("broker", &broker)])).or_insert(0.0)+=1.0;
+        *collected.entry(OPEN_SHARES.name).or_default().entry(labels(&[("broker", &broker), ("symbol", &d.stock_name)])).or_insert(0.0)+=d.num_stock as f64;
+    }
+    collected
+}
+
+// Every metric in the Prometheus text exposition format
+pub fn render() -> String{
+    let mut series = SERIES.lock().unwrap().clone();
+    series.extend(collect_positions());
+    let mut out = String::new();
+    for metric in ALL{
+        let kind = match metric.kind{Kind::Counter => "counter", Kind::Gauge => "gauge"};
+        out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", metric.name, metric.help, metric.name, kind));
+        for (labels, value) in series.get(metric.name).into_iter().flatten(){
+            render_series(&mut out, metric.name, labels, *value);
+        }
+    }
+    out
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()>{
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (status, body) = if path == "/metrics" || path == "/"{
+        ("200 OK", render())
+    }else{
+        ("404 Not Found", "not found\n".to_string())
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)
+}
+
+// Serve `/metrics` on `addr` (e.g. 127.0.0.1:9100) from a background thread
+pub fn serve(addr: &str) -> io::Result<()>{
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    logging::info(Component::Named("Metrics"), format!("serving http://{}/metrics", addr)).field("addr", addr.to_string()).emit();
+    thread::spawn(move||{
+        for stream in listener.incoming().flatten(){
+            respond(stream).unwrap_or_else(|err| logging::error(Component::Named("Metrics"), format!("failed to respond: {}", err)).emit());
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,174 @@
+// The exchange's news engine. Headlines are loaded from a file, each with a
+// sentiment, a magnitude and a scope (one symbol, a sector or the whole market).
+// Every round a random number of them breaks, and each adds volume to the stocks
+// in its scope: sold volume for bad news, bought volume for good news, which the
+// price model then turns into price moves.
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+use rand::{rngs::StdRng, Rng};
+use rand_distr::{Distribution, Poisson};
+use serde::{Deserialize, Serialize};
+use crate::Stock;
+
+// Built into the binary, used when no headlines file is configured
+const BUILTIN: &str = include_str!("../config/news.toml");
+
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Scope{
+    Market,
+    Sector(String),
+    Symbol(String),
+}
+
+impl Scope{
+    // "market", "sector:<name>" or "symbol:<name>"
+    pub fn parse(spec: &str) -> Result<Scope, String>{
+        match spec.trim().split_once(':'){
+            None if spec.trim() == "market" => Ok(Scope::Market),
+            Some(("sector", name)) if !name.trim().is_empty() => Ok(Scope::Sector(name.trim().to_string())),
+            Some(("symbol", name)) if !name.trim().is_empty() => Ok(Scope::Symbol(name.trim().to_string())),
+            _ => Err(format!("unknown scope {:?}, expected market, sector:<name> or symbol:<name>", spec)),
+        }
+    }
+}
+
+impl fmt::Display for Scope{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match self{
+            Scope::Market => write!(f, "market"),
+            Scope::Sector(name) => write!(f, "sector:{}", name),
+            Scope::Symbol(name) => write!(f, "symbol:{}", name),
+        }
+    }
+}
+
+impl TryFrom<String> for Scope{
+    type Error = String;
+    fn try_from(spec: String) -> Result<Scope, String>{
+        Scope::parse(&spec)
+    }
+}
+
+impl From<Scope> for String{
+    fn from(scope: Scope) -> String{
+        scope.to_string()
+    }
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Headline{
+    pub text: String,
+    pub sentiment: f64, // -1 (bad) to 1 (good)
+    pub magnitude: i64, // shares of volume per stock at full sentiment
+    pub scope: Scope,
+}
+
+impl Headline{
+    // Volume each affected stock gets, as (sold, bought)
+    pub fn volume(&self) -> (i128, i128){
+        let shares = (self.sentiment.abs() * self.magnitude as f64).round() as i128;
+        if self.sentiment < 0.0{(shares, 0)}else{(0, shares)}
+    }
+}
+
+// A headlines file
+#[derive(Clone,Debug,Default,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NewsFile{
+    pub sectors: BTreeMap<String, Vec<String>>, // sector name -> its symbols
+    pub headlines: Vec<Headline>,
+}
+
+impl NewsFile{
+    // A `.toml` or `.json` file, or the built-in headlines for an empty path
+    pub fn load(path: &str) -> Result<NewsFile, String>{
+        let file: NewsFile = if path.is_empty(){
+            toml::from_str(BUILTIN).map_err(|err| format!("built-in headlines: {}", err))?
+        }else{
+            let text = fs::read_to_string(path).map_err(|err| format!("can't read {}: {}", path, err))?;
+            match Path::new(path).extension().and_then(|ext| ext.to_str()){
+                Some("toml") => toml::from_str(&text).map_err(|err| format!("{}: {}", path, err))?,
+                Some("json") => serde_json::from_str(&text).map_err(|err| format!("{}: {}", path, err))?,
+                _ => return Err(format!("{}: expected a .toml or .json file", path)),
+            }
+        };
+        file.validate()?;
+        Ok(file)
+    }
+
+    pub fn validate(&self) -> Result<(), String>{
+        if self.headlines.is_empty(){return Err("no headlines".to_string());}
+        for h in self.headlines.iter(){
+            if h.text.is_empty(){return Err("a headline has no text".to_string());}
+            if !(-1.0..=1.0).contains(&h.sentiment){return Err(format!("{:?}: sentiment must be from -1 to 1", h.text));}
+            if h.magnitude < 0{return Err(format!("{:?}: magnitude can't be negative", h.text));}
+            if let Scope::Sector(sector) = &h.scope{
+                if !self.sectors.contains_key(sector){return Err(format!("{:?}: unknown sector {:?}", h.text, sector));}
+            }
+        }
+        Ok(())
+    }
+
+    // Listed stocks a headline affects
+    pub fn affected(&self, headline: &Headline, listed: &[Stock]) -> Vec<String>{
+        let in_scope = |name: &str| match &headline.scope{
+            Scope::Market => true,
+            Scope::Sector(sector) => self.sectors.get(sector).is_some_and(|symbols| symbols.iter().any(|s| s == name)),
+            Scope::Symbol(symbol) => symbol == name,
+        };
+        listed.iter().filter(|s| in_scope(&s.name)).map(|s| s.name.clone()).collect()
+    }
+}
+
+// `[news]` in the config file
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NewsConfig{
+    pub file: String, // headlines file, empty for the built-in ones
+    pub rate: f64, // mean headlines per exchange round (Poisson), 0 turns the news off
+    pub start_round: u32, // no news before this round
+    pub max_per_round: u32,
+}
+
+impl Default for NewsConfig{
+    fn default() -> Self {
+        NewsConfig{file:String::new(), rate:0.5, start_round:2, max_per_round:2}
+    }
+}
+
+impl NewsConfig{
+    pub fn validate(&self) -> Result<(), String>{
+        if !self.rate.is_finite() || self.rate < 0.0{return Err("rate can't be negative".to_string());}
+        NewsFile::load(&self.file).map(|_| ())
+    }
+}
+
+// A headline that broke, and the stocks it hit
+#[derive(Clone,Debug)]
+pub struct News{
+    pub headline: Headline,
+    pub affected: Vec<String>,
+}
+
+pub struct NewsEngine{
+    file: NewsFile,
+    config: NewsConfig,
+}
+
+impl NewsEngine{
+    pub fn new(config: &NewsConfig) -> Result<NewsEngine, String>{
+        Ok(NewsEngine{file:NewsFile::load(&config.file)?, config:config.clone()})
+    }
+
+    // The headlines breaking in this round, each drawn from those that hit a listed stock
+    pub fn draw(&self, round: u32, listed: &[Stock], rng: &mut StdRng) -> Vec<News>{
+        if round < self.config.start_round || self.config.rate <= 0.0{return Vec::new();}
+        let count = Poisson::new(self.config.rate).map(|p| p.sample(rng) as u32).unwrap_or(0).min(self.config.max_per_round);
+        let candidates: Vec<News> = self.file.headlines.iter()
+            .map(|h| News{headline:h.clone(), affected:self.file.affected(h, listed)})
+            .filter(|news| !news.affected.is_empty()).collect();
+        if candidates.is_empty(){return Vec::new();}
+        (0..count).map(|_| candidates[rng.gen_range(0..candidates.len())].clone()).collect()
+    }
+}
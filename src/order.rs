@@ -0,0 +1,92 @@
+use crate::message::{ExecutionReport, Order, OrderStatus, Side};
+use crate::PurchaseDetails;
+
+pub fn now_millis() -> i64{
+    chrono::Utc::now().timestamp_millis()
+}
+
+// A user's order as the broker sees it, moving from New to a final state.
+// Every transition returns the report to send back on the order's reply queue.
+pub struct WorkingOrder{
+    pub order: Order,
+    pub broker_no: i8,
+    pub status: OrderStatus,
+    pub cum_qty: i128,
+    pub armed: bool, // a stop order the broker holds until its price is reached
+    pub cancel_requested: bool, // the exchange was asked to take it off the book
+    pub replacement: Option<Order>, // sent once the cancel is confirmed
+    pub sold_from: Vec<PurchaseDetails>, // the holdings a sell of held shares took them from
+}
+
+impl WorkingOrder{
+    pub fn new(order: Order, broker_no: i8) -> WorkingOrder{
+        let armed = order.order_type.is_stop();
+        WorkingOrder{order, broker_no, status: OrderStatus::New, cum_qty: 0, armed, cancel_requested: false, replacement: None, sold_from: Vec::new()}
+    }
+
+    pub fn leaves_qty(&self) -> i128{
+        match self.status{
+            OrderStatus::Rejected{..} | OrderStatus::Cancelled | OrderStatus::Replaced{..} => 0,
+            _ => self.order.num_stock - self.cum_qty,
+        }
+    }
+
+    // Filled, rejected and cancelled orders can't change anymore
+    pub fn is_done(&self) -> bool{
+        matches!(self.status, OrderStatus::Filled | OrderStatus::Rejected{..} | OrderStatus::Cancelled | OrderStatus::Replaced{..})
+    }
+
+    pub fn accept(&mut self) -> ExecutionReport{
+        self.status = OrderStatus::Accepted;
+        self.report(0, 0.0, 0.0)
+    }
+
+    pub fn reject(&mut self, reason: &str) -> ExecutionReport{
+        self.status = OrderStatus::Rejected{reason: reason.to_string()};
+        self.report(0, 0.0, 0.0)
+    }
+
+    pub fn cancel(&mut self) -> ExecutionReport{
+        self.status = OrderStatus::Cancelled;
+        self.report(0, 0.0, 0.0)
+    }
+
+    pub fn replace(&mut self, by: &str) -> ExecutionReport{
+        self.status = OrderStatus::Replaced{by: by.to_string()};
+        self.report(0, 0.0, 0.0)
+    }
+
+    // The order carries on as it was
+    pub fn cancel_reject(&self, reason: &str) -> ExecutionReport{
+        ExecutionReport{status: OrderStatus::CancelRejected{reason: reason.to_string()}, ..self.report(0, 0.0, 0.0)}
+    }
+
+    pub fn fill(&mut self, qty: i128, price: f64, fee: f64) -> ExecutionReport{
+        self.cum_qty+=qty;
+        self.status = if self.cum_qty >= self.order.num_stock{OrderStatus::Filled}else{OrderStatus::PartiallyFilled};
+        self.report(qty, price, fee)
+    }
+
+    fn report(&self, last_qty: i128, last_price: f64, fee: f64) -> ExecutionReport{
+        ExecutionReport{
+            order_id: self.order.order_id.clone(),
+            client_id: self.order.client_id,
+            broker_no: self.broker_no,
+            stock_name: self.order.stock_name.clone(),
+            side: self.order.side,
+            status: self.status.clone(),
+            last_qty,
+            last_price,
+            cum_qty: self.cum_qty,
+            leaves_qty: self.leaves_qty(),
+            timestamp: now_millis(),
+            fee,
+        }
+    }
+}
+
+// Reply to a cancel, replace or amend the broker has no working order or holding for
+pub fn control_reply(order_id: &str, client_id: i8, broker_no: i8, stock_name: &str, side: Side, status: OrderStatus) -> ExecutionReport{
+    ExecutionReport{order_id: order_id.to_string(), client_id, broker_no, stock_name: stock_name.to_string(), side, status,
+        last_qty: 0, last_price: 0.0, cum_qty: 0, leaves_qty: 0, timestamp: now_millis(), fee: 0.0}
+}
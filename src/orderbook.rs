@@ -0,0 +1,260 @@
+use std::collections::{BTreeMap, VecDeque};
+use crate::message::{Execution, LimitOrder, OrderType, Side, TimeInForce};
+
+// Prices are kept in integer ticks so price levels can be used as map keys
+pub const TICK_SIZE: f64 = 0.01;
+
+pub fn to_ticks(price: f64) -> i64{
+    (price / TICK_SIZE).round() as i64
+}
+
+fn from_ticks(ticks: i64) -> f64{
+    ticks as f64 * TICK_SIZE
+}
+
+struct Resting{
+    order: LimitOrder,
+    leaves_qty: i128,
+}
+
+// Limit order book for one symbol with price-time priority.
+// Each price level is a FIFO queue, so the oldest order at the best price
+// always fills first. Orders never trade against resting orders from the
+// same broker and user; those are skipped and stay on the book.
+// Market orders take any price, and market, IOC and FOK orders never rest.
+pub struct OrderBook{
+    pub symbol: String,
+    bids: BTreeMap<i64, VecDeque<Resting>>,
+    asks: BTreeMap<i64, VecDeque<Resting>>,
+}
+
+impl OrderBook{
+    pub fn new(symbol: String) -> OrderBook{
+        OrderBook{symbol, bids: BTreeMap::new(), asks: BTreeMap::new()}
+    }
+
+    pub fn best_bid(&self) -> Option<f64>{
+        self.bids.keys().next_back().map(|&t| from_ticks(t))
+    }
+
+    pub fn best_ask(&self) -> Option<f64>{
+        self.asks.keys().next().map(|&t| from_ticks(t))
+    }
+
+    // Aggregated (price, quantity) levels, best price first
+    pub fn depth(&self, side: Side) -> Vec<(f64, i128)>{
+        let level = |(&t, q): (&i64, &VecDeque<Resting>)| (from_ticks(t), q.iter().map(|r| r.leaves_qty).sum());
+        match side{
+            Side::Buy => self.bids.iter().rev().map(level).collect(),
+            Side::Sell => self.asks.iter().map(level).collect(),
+        }
+    }
+
+    // Resting quantity an order could trade against right now
+    pub fn fillable(&self, order: &LimitOrder) -> i128{
+        let limit = to_ticks(order.price);
+        let market = order.order_type == OrderType::Market;
+        let levels: Vec<&VecDeque<Resting>> = match order.side{
+            Side::Buy => self.asks.iter().filter(|(&t, _)| market || t <= limit).map(|(_, q)| q).collect(),
+            Side::Sell => self.bids.iter().filter(|(&t, _)| market || t >= limit).map(|(_, q)| q).collect(),
+        };
+        levels.into_iter().flatten().filter(|r| !(r.order.broker_no == order.broker_no && r.order.user_id == order.user_id))
+            .map(|r| r.leaves_qty).sum()
+    }
+
+    // Match an incoming order against the opposite side, then rest whatever
+    // is left if the order rests. Returns one execution per side of every trade,
+    // maker first, and nothing for a FOK order that can't fill completely.
+    pub fn submit(&mut self, order: LimitOrder) -> Vec<Execution>{
+        if order.time_in_force == TimeInForce::Fok && self.fillable(&order) < order.num_stock{return Vec::new();}
+        let limit = to_ticks(order.price);
+        let (low, high) = match order.order_type{
+            OrderType::Market => (i64::MIN, i64::MAX),
+            _ => (limit, limit),
+        };
+        let mut leaves_qty = order.num_stock;
+        let mut executions = Vec::new();
+        let (opposite, own) = match order.side{
+            Side::Buy => (&mut self.asks, &mut self.bids),
+            Side::Sell => (&mut self.bids, &mut self.asks),
+        };
+        let levels: Vec<i64> = match order.side{
+            Side::Buy => opposite.range(..=high).map(|(&t, _)| t).collect(),
+            Side::Sell => opposite.range(low..).rev().map(|(&t, _)| t).collect(),
+        };
+        for level in levels{
+            if leaves_qty == 0{break;}
+            let queue = opposite.get_mut(&level).unwrap();
+            let mut i = 0;
+            while i < queue.len() && leaves_qty > 0{
+                let resting = &mut queue[i];
+                if resting.order.broker_no == order.broker_no && resting.order.user_id == order.user_id{
+                    i+=1;
+                    continue;
+                }
+                let qty = leaves_qty.min(resting.leaves_qty);
+                resting.leaves_qty-=qty;
+                leaves_qty-=qty;
+                // trades at the resting order's price
+                let price = from_ticks(level);
+                executions.push(execution(&resting.order, price, qty, resting.leaves_qty, false));
+                executions.push(execution(&order, price, qty, leaves_qty, true));
+                if resting.leaves_qty == 0{
+                    queue.remove(i);
+                }else{
+                    i+=1;
+                }
+            }
+            if queue.is_empty(){
+                opposite.remove(&level);
+            }
+        }
+        if leaves_qty > 0 && order.rests(){
+            own.entry(limit).or_default().push_back(Resting{order, leaves_qty});
+        }
+        executions
+    }
+
+    // Remove every resting order matching the filter, returning what was left of them
+    pub fn cancel_where<F: Fn(&LimitOrder) -> bool>(&mut self, filter: F) -> Vec<(LimitOrder, i128)>{
+        let mut cancelled = Vec::new();
+        for levels in [&mut self.bids, &mut self.asks]{
+            for queue in levels.values_mut(){
+                let mut kept = VecDeque::new();
+                for resting in queue.drain(..){
+                    if filter(&resting.order){
+                        cancelled.push((resting.order, resting.leaves_qty));
+                    }else{
+                        kept.push_back(resting);
+                    }
+                }
+                *queue = kept;
+            }
+            levels.retain(|_, queue| !queue.is_empty());
+        }
+        cancelled
+    }
+}
+
+fn execution(order: &LimitOrder, price: f64, num_stock: i128, leaves_qty: i128, aggressor: bool) -> Execution{
+    Execution{
+        order_id: order.order_id.clone(),
+        broker_no: order.broker_no,
+        user_id: order.user_id,
+        stock_name: order.stock_name.clone(),
+        side: order.side,
+        price,
+        num_stock,
+        leaves_qty,
+        aggressor,
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn order(id: &str, broker_no: i8, user_id: i8, side: Side, price: f64, num_stock: i128) -> LimitOrder{
+        LimitOrder{order_id:id.to_string(), broker_no, user_id, stock_name:"AAPL".to_string(), side, price, num_stock,
+            order_type:OrderType::Limit, time_in_force:TimeInForce::Gtc}
+    }
+
+    fn with(order: LimitOrder, order_type: OrderType, time_in_force: TimeInForce) -> LimitOrder{
+        LimitOrder{order_type, time_in_force, ..order}
+    }
+
+    // (order id, quantity, price) of the executions on one side of the trades
+    fn fills(executions: &[Execution], aggressor: bool) -> Vec<(String, i128, f64)>{
+        executions.iter().filter(|e| e.aggressor == aggressor).map(|e| (e.order_id.clone(), e.num_stock, e.price)).collect()
+    }
+
+    #[test]
+    fn best_price_fills_first_then_oldest(){
+        let mut book = OrderBook::new("AAPL".to_string());
+        book.submit(order("a1", 1, 1, Side::Sell, 101.0, 5));
+        book.submit(order("a2", 1, 2, Side::Sell, 100.0, 5));
+        book.submit(order("a3", 2, 3, Side::Sell, 100.0, 5));
+        let executions = book.submit(order("b", 2, 4, Side::Buy, 101.0, 12));
+        assert_eq!(fills(&executions, false), vec![("a2".to_string(), 5, 100.0), ("a3".to_string(), 5, 100.0), ("a1".to_string(), 2, 101.0)]);
+        assert_eq!(fills(&executions, true).iter().map(|f| f.1).sum::<i128>(), 12);
+        assert_eq!(book.depth(Side::Sell), vec![(101.0, 3)]);
+        assert!(book.best_bid().is_none());
+    }
+
+    #[test]
+    fn partial_fill_rests_the_rest_at_its_limit(){
+        let mut book = OrderBook::new("AAPL".to_string());
+        book.submit(order("a", 1, 1, Side::Sell, 100.0, 4));
+        let executions = book.submit(order("b", 2, 2, Side::Buy, 100.5, 10));
+        let taker: Vec<&Execution> = executions.iter().filter(|e| e.aggressor).collect();
+        assert_eq!(taker.len(), 1);
+        assert_eq!((taker[0].num_stock, taker[0].leaves_qty, taker[0].price), (4, 6, 100.0));
+        assert_eq!(book.depth(Side::Buy), vec![(100.5, 6)]);
+        assert_eq!(book.best_ask(), None);
+    }
+
+    #[test]
+    fn limit_doesnt_cross_a_worse_price(){
+        let mut book = OrderBook::new("AAPL".to_string());
+        book.submit(order("a", 1, 1, Side::Sell, 101.0, 5));
+        assert!(book.submit(order("b", 2, 2, Side::Buy, 100.0, 5)).is_empty());
+        assert_eq!((book.best_bid(), book.best_ask()), (Some(100.0), Some(101.0)));
+    }
+
+    #[test]
+    fn market_takes_any_price_and_never_rests(){
+        let mut book = OrderBook::new("AAPL".to_string());
+        book.submit(order("a1", 1, 1, Side::Sell, 100.0, 3));
+        book.submit(order("a2", 1, 1, Side::Sell, 150.0, 3));
+        let executions = book.submit(with(order("m", 2, 2, Side::Buy, 1.0, 10), OrderType::Market, TimeInForce::Gtc));
+        assert_eq!(fills(&executions, true), vec![("m".to_string(), 3, 100.0), ("m".to_string(), 3, 150.0)]);
+        assert!(book.depth(Side::Buy).is_empty());
+        assert!(book.depth(Side::Sell).is_empty());
+    }
+
+    #[test]
+    fn ioc_remainder_is_dropped(){
+        let mut book = OrderBook::new("AAPL".to_string());
+        book.submit(order("a", 1, 1, Side::Sell, 100.0, 3));
+        let executions = book.submit(with(order("i", 2, 2, Side::Buy, 100.0, 5), OrderType::Limit, TimeInForce::Ioc));
+        assert_eq!(fills(&executions, true), vec![("i".to_string(), 3, 100.0)]);
+        assert!(book.depth(Side::Buy).is_empty());
+    }
+
+    #[test]
+    fn fok_fills_completely_or_not_at_all(){
+        let mut book = OrderBook::new("AAPL".to_string());
+        book.submit(order("a", 1, 1, Side::Sell, 100.0, 3));
+        assert!(book.submit(with(order("f1", 2, 2, Side::Buy, 100.0, 5), OrderType::Limit, TimeInForce::Fok)).is_empty());
+        assert_eq!(book.depth(Side::Sell), vec![(100.0, 3)]);
+        let executions = book.submit(with(order("f2", 2, 2, Side::Buy, 100.0, 3), OrderType::Limit, TimeInForce::Fok));
+        assert_eq!(fills(&executions, true), vec![("f2".to_string(), 3, 100.0)]);
+        assert!(book.depth(Side::Sell).is_empty());
+    }
+
+    #[test]
+    fn own_orders_are_skipped_and_stay_on_the_book(){
+        let mut book = OrderBook::new("AAPL".to_string());
+        book.submit(order("own", 1, 1, Side::Sell, 100.0, 5));
+        book.submit(order("other", 2, 1, Side::Sell, 100.0, 5));
+        let buy = order("b", 1, 1, Side::Buy, 100.0, 8);
+        assert_eq!(book.fillable(&buy), 5);
+        let executions = book.submit(buy);
+        assert_eq!(fills(&executions, false), vec![("other".to_string(), 5, 100.0)]);
+        assert_eq!(book.depth(Side::Sell), vec![(100.0, 5)]);
+        assert_eq!(book.depth(Side::Buy), vec![(100.0, 3)]);
+    }
+
+    #[test]
+    fn cancel_where_returns_what_was_left(){
+        let mut book = OrderBook::new("AAPL".to_string());
+        book.submit(with(order("d", 1, 1, Side::Buy, 99.0, 10), OrderType::Limit, TimeInForce::Day));
+        book.submit(order("g", 1, 2, Side::Buy, 98.0, 10));
+        book.submit(order("s", 2, 3, Side::Sell, 99.0, 4));
+        // the day's close, as the exchange does it
+        let cancelled = book.cancel_where(|o| o.time_in_force == TimeInForce::Day);
+        assert_eq!(cancelled.iter().map(|(o, leaves)| (o.order_id.as_str(), *leaves)).collect::<Vec<_>>(), vec![("d", 6)]);
+        assert_eq!(book.depth(Side::Buy), vec![(98.0, 10)]);
+        assert!(book.cancel_where(|o| o.order_id == "d").is_empty());
+    }
+}
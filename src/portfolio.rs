@@ -0,0 +1,231 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::message::{Execution, Side};
+use crate::Stock;
+
+// Cash every user starts with
+pub const INITIAL_CASH: f64 = 20_000.0;
+
+#[derive(Clone,Debug,Default,Serialize,Deserialize)]
+pub struct Position{
+    pub stock_name: String,
+    pub quantity: i128, // negative when short
+    pub avg_cost: f64,
+    pub realised_pnl: f64,
+}
+
+impl Position{
+    // Average cost accounting: adding to a position moves the average,
+    // reducing it realises P&L against the average
+    fn apply(&mut self, side: Side, qty: i128, price: f64){
+        let signed = match side{Side::Buy => qty, Side::Sell => -qty};
+        if self.quantity == 0 || self.quantity.signum() == signed.signum(){
+            let total = self.quantity.abs() + qty;
+            self.avg_cost = (self.avg_cost * self.quantity.abs() as f64 + price * qty as f64) / total as f64;
+            self.quantity+=signed;
+            return;
+        }
+        let closed = qty.min(self.quantity.abs());
+        self.realised_pnl+=(price - self.avg_cost) * (closed * self.quantity.signum()) as f64;
+        self.quantity+=signed;
+        if self.quantity == 0{
+            self.avg_cost = 0.0;
+        }else if self.quantity.signum() == signed.signum(){
+            // flipped from long to short (or back), the rest opens at this price
+            self.avg_cost = price;
+        }
+    }
+
+    pub fn unrealised_pnl(&self, last_price: f64) -> f64{
+        (last_price - self.avg_cost) * self.quantity as f64
+    }
+}
+
+#[derive(Clone,Debug,Default)]
+pub struct Account{
+    pub user_id: i8,
+    pub cash: f64,
+    pub fees: f64, // commission & markup paid to the brokers
+    positions: HashMap<String, Position>,
+    reservations: HashMap<String, f64>, // cash held for working buy orders, by order id
+}
+
+impl Account{
+    pub fn reserved(&self) -> f64{
+        self.reservations.values().sum()
+    }
+
+    pub fn available_cash(&self) -> f64{
+        self.cash - self.reserved()
+    }
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct PositionSnapshot{
+    pub position: Position,
+    pub last_price: f64,
+    pub market_value: f64,
+    pub unrealised_pnl: f64,
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct PortfolioSnapshot{
+    pub user_id: i8,
+    pub cash: f64,
+    pub reserved: f64,
+    pub fees: f64,
+    pub positions: Vec<PositionSnapshot>,
+    pub realised_pnl: f64,
+    pub unrealised_pnl: f64,
+    pub equity: f64, // cash + market value of the positions
+}
+
+// Every user's cash and positions, keyed by user. Accounts are opened with
+// the initial cash the first time a user shows up.
+// A broker of its own process books every broker's fills in its own portfolio, so
+// positions add up, but its cash reservations only cover its own orders: a user can
+// hold their available cash at each broker. Brokers sharing one process share one
+// portfolio instead, each booking only its own fills.
+pub struct Portfolio{
+    initial_cash: f64,
+    accounts: HashMap<i8, Account>,
+    shared: bool,
+}
+
+impl Portfolio{
+    pub fn new(initial_cash: f64) -> Portfolio{
+        Portfolio{initial_cash, accounts: HashMap::new(), shared: false}
+    }
+
+    // One portfolio for every broker of the process
+    pub fn shared(initial_cash: f64) -> Portfolio{
+        Portfolio{shared: true, ..Portfolio::new(initial_cash)}
+    }
+
+    // Whether a fill should be booked by the broker that got it
+    pub fn books(&self, exec: &Execution, broker_no: i8) -> bool{
+        !self.shared || exec.broker_no == broker_no
+    }
+
+    pub fn account(&mut self, user_id: i8) -> &mut Account{
+        let initial_cash = self.initial_cash;
+        self.accounts.entry(user_id).or_insert_with(|| Account{user_id, cash:initial_cash, ..Default::default()})
+    }
+
+    pub fn user_ids(&self) -> Vec<i8>{
+        let mut ids: Vec<i8> = self.accounts.keys().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    // Hold cash for a buy order, fails with the available cash if there isn't enough
+    pub fn try_reserve(&mut self, user_id: i8, order_id: &str, amount: f64) -> Result<(), f64>{
+        let account = self.account(user_id);
+        let available = account.available_cash();
+        if amount > available{
+            return Err(available);
+        }
+        account.reservations.insert(order_id.to_string(), amount);
+        Ok(())
+    }
+
+    // Give back part of a reservation (None releases all of it)
+    pub fn release(&mut self, user_id: i8, order_id: &str, amount: Option<f64>){
+        let account = self.account(user_id);
+        if let Some(reserved) = account.reservations.get_mut(order_id){
+            *reserved-=amount.unwrap_or(*reserved).min(*reserved);
+            if *reserved <= 0.0{account.reservations.remove(order_id);}
+        }
+    }
+
+    // `fee` is what the handling broker charges on top of the fill
+    pub fn apply_fill(&mut self, exec: &Execution, fee: f64){
+        let account = self.account(exec.user_id);
+        let notional = exec.price * exec.num_stock as f64;
+        match exec.side{
+            Side::Buy => account.cash-=notional,
+            Side::Sell => account.cash+=notional,
+        }
+        account.cash-=fee;
+        account.fees+=fee;
+        let position = account.positions.entry(exec.stock_name.clone())
+            .or_insert_with(|| Position{stock_name:exec.stock_name.clone(), ..Default::default()});
+        position.apply(exec.side, exec.num_stock, exec.price);
+    }
+
+    // Marks every position to the latest stock list
+    pub fn snapshot(&self, user_id: i8, stock_list: &[Stock]) -> Option<PortfolioSnapshot>{
+        let account = self.accounts.get(&user_id)?;
+        let mut positions: Vec<PositionSnapshot> = account.positions.values().map(|p| {
+            let last_price = stock_list.iter().find(|s| s.name == p.stock_name).map(|s| s.value).unwrap_or(p.avg_cost);
+            PositionSnapshot{position:p.clone(), last_price, market_value:last_price * p.quantity as f64, unrealised_pnl:p.unrealised_pnl(last_price)}
+        }).collect();
+        positions.sort_by(|a, b| a.position.stock_name.cmp(&b.position.stock_name));
+        let market_value: f64 = positions.iter().map(|p| p.market_value).sum();
+        Some(PortfolioSnapshot{
+            user_id,
+            cash: account.cash,
+            reserved: account.reserved(),
+            fees: account.fees,
+            realised_pnl: positions.iter().map(|p| p.position.realised_pnl).sum(),
+            unrealised_pnl: positions.iter().map(|p| p.unrealised_pnl).sum(),
+            equity: account.cash + market_value,
+            positions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn exec(broker_no: i8, side: Side, num_stock: i128, price: f64) -> Execution{
+        Execution{order_id:"U1-1".to_string(), broker_no, user_id:1, stock_name:"AAPL".to_string(), side, price, num_stock, leaves_qty:0, aggressor:true}
+    }
+
+    #[test]
+    fn average_cost_moves_on_adds(){
+        let mut p = Position::default();
+        p.apply(Side::Buy, 10, 100.0);
+        p.apply(Side::Buy, 10, 110.0);
+        assert_eq!((p.quantity, p.avg_cost, p.realised_pnl), (20, 105.0, 0.0));
+        p.apply(Side::Sell, 5, 115.0);
+        assert_eq!((p.quantity, p.avg_cost, p.realised_pnl), (15, 105.0, 50.0));
+    }
+
+    #[test]
+    fn flip_from_long_to_short_opens_the_rest_at_the_fill_price(){
+        let mut p = Position::default();
+        p.apply(Side::Buy, 10, 100.0);
+        p.apply(Side::Sell, 15, 90.0);
+        assert_eq!((p.quantity, p.avg_cost, p.realised_pnl), (-5, 90.0, -100.0));
+        assert_eq!(p.unrealised_pnl(80.0), 50.0);
+        // covering the short realises against its own average
+        p.apply(Side::Buy, 5, 80.0);
+        assert_eq!((p.quantity, p.avg_cost, p.realised_pnl), (0, 0.0, -50.0));
+    }
+
+    #[test]
+    fn reservations_limit_available_cash(){
+        let mut portfolio = Portfolio::new(1000.0);
+        assert!(portfolio.try_reserve(1, "U1-1", 600.0).is_ok());
+        assert_eq!(portfolio.try_reserve(1, "U1-2", 600.0), Err(400.0));
+        portfolio.release(1, "U1-1", Some(200.0));
+        assert_eq!(portfolio.account(1).available_cash(), 600.0);
+        portfolio.release(1, "U1-1", None);
+        assert_eq!(portfolio.account(1).reserved(), 0.0);
+    }
+
+    #[test]
+    fn shared_portfolio_books_each_fill_once(){
+        let fill = exec(2, Side::Buy, 10, 100.0);
+        let shared = Portfolio::shared(1000.0);
+        assert!(shared.books(&fill, 2) && !shared.books(&fill, 1));
+        let own = Portfolio::new(1000.0);
+        assert!(own.books(&fill, 1) && own.books(&fill, 2));
+        let mut portfolio = Portfolio::new(2000.0);
+        portfolio.apply_fill(&fill, 5.0);
+        let snapshot = portfolio.snapshot(1, &[Stock{name:"AAPL".to_string(), value:110.0}]).unwrap();
+        assert_eq!((snapshot.cash, snapshot.fees, snapshot.equity, snapshot.unrealised_pnl), (995.0, 5.0, 2095.0, 100.0));
+    }
+}
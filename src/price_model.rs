@@ -0,0 +1,226 @@
+use std::fmt;
+use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+use crate::orderbook::TICK_SIZE;
+
+// Lowest price a stock can be quoted at, whatever the model says
+pub const MIN_PRICE: f64 = TICK_SIZE;
+
+// Result of one exchange tick for one stock
+pub struct PriceMove{
+    pub price: f64,
+    pub buy_used: i128, // volume the model consumed, the rest carries over
+    pub sold_used: i128,
+}
+
+// How the exchange turns traded volume into a new price
+pub trait PriceModel: Send{
+    fn next_price(&mut self, price: f64, buy_vol: i128, sold_vol: i128) -> PriceMove;
+}
+
+// The original rule: +10% for every 30 shares bought, -10% for every 15 sold
+pub struct StepModel{
+    pub buy_step: i128,
+    pub sell_step: i128,
+    pub pct: f64,
+}
+
+impl PriceModel for StepModel{
+    fn next_price(&mut self, price: f64, buy_vol: i128, sold_vol: i128) -> PriceMove{
+        let up = buy_vol/self.buy_step; // same logic like if buy_vol >= buy_step
+        let down = sold_vol/self.sell_step;
+        let mut new_price = price;
+        new_price *= 1.0+(self.pct*(up as f64));
+        new_price *= 1.0-(self.pct*(down as f64));
+        PriceMove{price:new_price.max(MIN_PRICE), buy_used:up*self.buy_step, sold_used:down*self.sell_step}
+    }
+}
+
+// Relative price change proportional to the net volume
+pub struct LinearImpact{
+    pub impact: f64,
+}
+
+impl PriceModel for LinearImpact{
+    fn next_price(&mut self, price: f64, buy_vol: i128, sold_vol: i128) -> PriceMove{
+        let net = (buy_vol - sold_vol) as f64;
+        let new_price = price * (1.0 + self.impact * net);
+        PriceMove{price:new_price.max(MIN_PRICE), buy_used:buy_vol, sold_used:sold_vol}
+    }
+}
+
+// Relative price change proportional to the square root of the net volume,
+// so big orders move the price less than linearly
+pub struct SquareRootImpact{
+    pub impact: f64,
+}
+
+impl PriceModel for SquareRootImpact{
+    fn next_price(&mut self, price: f64, buy_vol: i128, sold_vol: i128) -> PriceMove{
+        let net = (buy_vol - sold_vol) as f64;
+        let new_price = price * (1.0 + self.impact * net.signum() * net.abs().sqrt());
+        PriceMove{price:new_price.max(MIN_PRICE), buy_used:buy_vol, sold_used:sold_vol}
+    }
+}
+
+// Geometric Brownian motion, the price drifts and diffuses every tick
+// whatever the traded volume is
+pub struct GeometricBrownianMotion{
+    pub drift: f64,
+    pub volatility: f64,
+    pub dt: f64, // length of one tick, in the same unit as drift and volatility
+    rng: StdRng,
+}
+
+impl GeometricBrownianMotion{
+    pub fn new(drift: f64, volatility: f64, dt: f64, rng: StdRng) -> GeometricBrownianMotion{
+        GeometricBrownianMotion{drift, volatility, dt, rng}
+    }
+}
+
+impl PriceModel for GeometricBrownianMotion{
+    fn next_price(&mut self, price: f64, buy_vol: i128, sold_vol: i128) -> PriceMove{
+        let z: f64 = self.rng.sample(StandardNormal);
+        let exponent = (self.drift - self.volatility.powi(2) / 2.0) * self.dt + self.volatility * self.dt.sqrt() * z;
+        PriceMove{price:(price * exponent.exp()).max(MIN_PRICE), buy_used:buy_vol, sold_used:sold_vol}
+    }
+}
+
+// Price model picked at startup
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum PriceModelConfig{
+    Step{buy_step: i64, sell_step: i64, pct: f64}, // i64 so it fits in a TOML file
+    Linear{impact: f64},
+    SquareRoot{impact: f64},
+    Gbm{drift: f64, volatility: f64, dt: f64},
+}
+
+impl Default for PriceModelConfig{
+    fn default() -> Self {
+        PriceModelConfig::Step{buy_step:30, sell_step:15, pct:0.1}
+    }
+}
+
+impl PriceModelConfig{
+    // Parse `step[:buy_step:sell_step:pct]`, `linear[:impact]`, `sqrt[:impact]`
+    // or `gbm[:drift:volatility:dt]`, missing parameters take their defaults
+    pub fn parse(spec: &str) -> Result<PriceModelConfig, String>{
+        let mut parts = spec.split(':');
+        let name = parts.next().unwrap_or_default();
+        let params = parts.map(|p| p.parse::<f64>().map_err(|_| format!("invalid price model parameter '{}'", p)))
+            .collect::<Result<Vec<f64>, String>>()?;
+        let param = |i: usize, default: f64| params.get(i).copied().unwrap_or(default);
+        let config = match name{
+            "step" => PriceModelConfig::Step{buy_step:param(0, 30.0) as i64, sell_step:param(1, 15.0) as i64, pct:param(2, 0.1)},
+            "linear" => PriceModelConfig::Linear{impact:param(0, 0.002)},
+            "sqrt" => PriceModelConfig::SquareRoot{impact:param(0, 0.01)},
+            "gbm" => PriceModelConfig::Gbm{drift:param(0, 0.0), volatility:param(1, 0.2), dt:param(2, 1.0 / 252.0)},
+            other => return Err(format!("unknown price model '{}'", other)),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String>{
+        match *self{
+            PriceModelConfig::Step{buy_step, sell_step, pct} => {
+                if buy_step <= 0 || sell_step <= 0{return Err("step volumes must be positive".to_string());}
+                if !(0.0..1.0).contains(&pct){return Err("step pct must be in [0, 1)".to_string());}
+            }
+            PriceModelConfig::Linear{impact} | PriceModelConfig::SquareRoot{impact} => {
+                if !impact.is_finite() || impact < 0.0{return Err("impact must be a non-negative number".to_string());}
+            }
+            PriceModelConfig::Gbm{drift, volatility, dt} => {
+                if !drift.is_finite() || !volatility.is_finite() || volatility < 0.0{return Err("gbm drift/volatility are invalid".to_string());}
+                if !dt.is_finite() || dt <= 0.0{return Err("gbm dt must be positive".to_string());}
+            }
+        }
+        Ok(())
+    }
+
+    // `rng` is only used by the models with a random component
+    pub fn build(&self, rng: StdRng) -> Box<dyn PriceModel>{
+        match *self{
+            PriceModelConfig::Step{buy_step, sell_step, pct} => Box::new(StepModel{buy_step:buy_step.into(), sell_step:sell_step.into(), pct}),
+            PriceModelConfig::Linear{impact} => Box::new(LinearImpact{impact}),
+            PriceModelConfig::SquareRoot{impact} => Box::new(SquareRootImpact{impact}),
+            PriceModelConfig::Gbm{drift, volatility, dt} => Box::new(GeometricBrownianMotion::new(drift, volatility, dt, rng)),
+        }
+    }
+}
+
+impl fmt::Display for PriceModelConfig{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            PriceModelConfig::Step{buy_step, sell_step, pct} => write!(f, "step (±{}% per {} bought / {} sold)", pct * 100.0, buy_step, sell_step),
+            PriceModelConfig::Linear{impact} => write!(f, "linear impact ({})", impact),
+            PriceModelConfig::SquareRoot{impact} => write!(f, "square-root impact ({})", impact),
+            PriceModelConfig::Gbm{drift, volatility, dt} => write!(f, "GBM (drift {}, volatility {}, dt {})", drift, volatility, dt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::rng::{component_rng, PRICE_MODEL_STREAM};
+
+    fn close(a: f64, b: f64) -> bool{
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn step_moves_per_whole_step(){
+        let mut model = StepModel{buy_step:30, sell_step:15, pct:0.1};
+        let up = model.next_price(100.0, 65, 0);
+        assert!(close(up.price, 120.0));
+        assert_eq!((up.buy_used, up.sold_used), (60, 0));
+        let down = model.next_price(100.0, 0, 20);
+        assert!(close(down.price, 90.0));
+        assert_eq!(down.sold_used, 15);
+    }
+
+    #[test]
+    fn impact_models_follow_the_net_volume(){
+        assert!(close(LinearImpact{impact:0.002}.next_price(100.0, 150, 50).price, 120.0));
+        assert!(close(LinearImpact{impact:0.002}.next_price(100.0, 0, 50).price, 90.0));
+        assert!(close(SquareRootImpact{impact:0.01}.next_price(100.0, 100, 0).price, 110.0));
+        assert!(close(SquareRootImpact{impact:0.01}.next_price(100.0, 0, 100).price, 90.0));
+    }
+
+    #[test]
+    fn gbm_without_volatility_is_its_drift(){
+        let mut model = GeometricBrownianMotion::new(0.1, 0.0, 1.0, component_rng(1, PRICE_MODEL_STREAM));
+        let moved = model.next_price(100.0, 7, 3);
+        assert!(close(moved.price, 100.0 * 0.1f64.exp()));
+        assert_eq!((moved.buy_used, moved.sold_used), (7, 3));
+    }
+
+    #[test]
+    fn a_big_sell_off_keeps_every_price_positive(){
+        let mut models: Vec<Box<dyn PriceModel>> = vec![
+            Box::new(StepModel{buy_step:30, sell_step:15, pct:0.1}),
+            Box::new(LinearImpact{impact:0.002}),
+            Box::new(SquareRootImpact{impact:0.01}),
+            Box::new(GeometricBrownianMotion::new(-1000.0, 5.0, 1.0, component_rng(7, PRICE_MODEL_STREAM))),
+        ];
+        for model in models.iter_mut(){
+            let mut price = 100.0;
+            for _ in 0..20{
+                price = model.next_price(price, 0, 1_000_000).price;
+                assert!(price >= MIN_PRICE, "{}", price);
+            }
+        }
+    }
+
+    #[test]
+    fn specs_parse_with_defaults(){
+        assert_eq!(PriceModelConfig::parse("step"), Ok(PriceModelConfig::default()));
+        assert_eq!(PriceModelConfig::parse("linear:0.01"), Ok(PriceModelConfig::Linear{impact:0.01}));
+        assert!(PriceModelConfig::parse("step:0").is_err());
+        assert!(PriceModelConfig::parse("gbm:0:0.2:0").is_err());
+        assert!(PriceModelConfig::parse("random").is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use std::{fmt, sync::{Arc, Mutex}};
+use serde::{Deserialize, Serialize};
+use crate::journal::{self, Event};
+use crate::logging::{self, Component, Tone};
+use crate::order::now_millis;
+use crate::{Order, Stock};
+
+// How far a trailing stop follows below the highest price since purchase
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TrailingStop{
+    Percent(f64), // fraction of the high, "5%"
+    Amount(f64), // in price, "2.5"
+}
+
+impl TrailingStop{
+    // "<pct>%" or an absolute amount
+    pub fn parse(spec: &str) -> Result<TrailingStop, String>{
+        let spec = spec.trim();
+        let stop = match spec.strip_suffix('%'){
+            Some(pct) => TrailingStop::Percent(pct.trim().parse::<f64>().map_err(|_| format!("invalid trailing stop {:?}", spec))? / 100.0),
+            None => TrailingStop::Amount(spec.parse::<f64>().map_err(|_| format!("invalid trailing stop {:?}, expected e.g. 5% or 2.5", spec))?),
+        };
+        match stop{
+            TrailingStop::Percent(pct) if !(pct > 0.0 && pct < 1.0) => Err(format!("trailing stop {:?} must be between 0% and 100%", spec)),
+            TrailingStop::Amount(amount) if !(amount.is_finite() && amount > 0.0) => Err(format!("trailing stop {:?} must be positive", spec)),
+            stop => Ok(stop),
+        }
+    }
+
+    // The stop price for a high
+    pub fn level(&self, high: f64) -> f64{
+        match *self{
+            TrailingStop::Percent(pct) => high * (1.0 - pct),
+            TrailingStop::Amount(amount) => high - amount,
+        }
+    }
+}
+
+impl fmt::Display for TrailingStop{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        match *self{
+            TrailingStop::Percent(pct) => write!(f, "{}%", pct * 100.0),
+            TrailingStop::Amount(amount) => write!(f, "{}", amount),
+        }
+    }
+}
+
+impl TryFrom<String> for TrailingStop{
+    type Error = String;
+    fn try_from(spec: String) -> Result<TrailingStop, String>{
+        TrailingStop::parse(&spec)
+    }
+}
+
+impl From<TrailingStop> for String{
+    fn from(stop: TrailingStop) -> String{
+        stop.to_string()
+    }
+}
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct PurchaseDetails{
+    pub broker_no:i8, // brokers may share one process (in-memory transport)
+    pub id:i8,
+    pub stock_name: String,
+    pub take_profit:f64,
+    pub cut_loss:f64,
+    pub num_stock:i128,
+    pub reply_to:String, // user's execution report queue
+    // the legs below and the two above are one OCO bracket, the first to trigger sells the holding and cancels the rest
+    #[serde(default)]
+    pub trailing_stop:Option<TrailingStop>,
+    #[serde(default)]
+    pub high_water:f64, // highest price since purchase, the trailing stop follows it
+    #[serde(default)]
+    pub expires_at:Option<i64>, // ms since epoch, the holding is sold at the market then
+}
+
+lazy_static::lazy_static!{
+    static ref PURCHASE_HISTORY: Arc<Mutex<Vec<PurchaseDetails>>> = Arc::new(Mutex::new(Vec::new()));
+}
+
+// Why the broker sold a holding on its own
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+pub enum SellReason{
+    CutLoss,
+    TakeProfit,
+    TrailingStop,
+    Expired,
+}
+
+impl PurchaseDetails{
+    // The holding a buy order's fill leaves, with the order's exit bracket
+    pub fn for_fill(broker_no: i8,order: &Order,num_stock:i128,price:f64) -> PurchaseDetails{
+        PurchaseDetails{broker_no,id:order.client_id,stock_name:order.stock_name.clone(),take_profit:order.take_profit,cut_loss:order.cut_loss,
+            num_stock,reply_to:order.reply_to.clone(),trailing_stop:order.trailing_stop,high_water:price,
+            expires_at:order.expire_after_ms.map(|ms| now_millis() + ms)}
+    }
+
+   pub fn add_order(details: PurchaseDetails) {
+        journal::record(&format!("broker {}", details.broker_no), Event::PositionAdded{details:details.clone()});
+        merge_order(&mut PURCHASE_HISTORY.lock().unwrap(), details);
+   }
+
+    // Take shares the user is selling out of their records, returns the part of each
+    // record taken, to be put back if the sell doesn't go through
+    pub fn reduce_order(broker_no: i8,id: i8,stock_name:&str,num_stock:i128) -> Vec<PurchaseDetails> {
+        journal::record(&format!("broker {}", broker_no), Event::PositionReduced{broker_no, user_id:id, stock_name:stock_name.to_string(), num_stock});
+        reduce_records(&mut PURCHASE_HISTORY.lock().unwrap(), broker_no, id, stock_name, num_stock)
+    }
+
+    // Put `num_stock` of the shares taken for a sell back under watch with the levels they had
+    pub fn restore(taken: &[PurchaseDetails], num_stock: i128){
+        let mut left = num_stock;
+        for d in taken.iter().rev(){
+            if left <= 0{break;}
+            let qty = d.num_stock.min(left);
+            left-=qty;
+            PurchaseDetails::add_order(PurchaseDetails{num_stock:qty, ..d.clone()});
+        }
+    }
+
+    // Shares of the stock the user holds at the broker, under watch
+    pub fn held(broker_no: i8,id: i8,stock_name:&str) -> i128{
+        PURCHASE_HISTORY.lock().unwrap().iter().filter(|d| d.broker_no == broker_no && d.id == id && d.stock_name == stock_name).map(|d| d.num_stock).sum()
+    }
+
+    // New protective levels for the user's holding at the broker, None keeps a level
+    pub fn amend_levels(broker_no: i8,id: i8,stock_name:&str,take_profit:Option<f64>,cut_loss:Option<f64>) -> Result<(), String>{
+        amend_records(&mut PURCHASE_HISTORY.lock().unwrap(), broker_no, id, stock_name, take_profit, cut_loss)?;
+        journal::record(&format!("broker {}", broker_no), Event::LevelsAmended{broker_no, user_id:id, stock_name:stock_name.to_string(), take_profit, cut_loss});
+        Ok(())
+    }
+
+    // Every holding under watch, for the metrics
+    pub fn open_positions() -> Vec<PurchaseDetails>{
+        PURCHASE_HISTORY.lock().unwrap().clone()
+    }
+
+    // Stop watching the broker's holdings when it shuts down, returns them
+    pub fn flush(broker_no: i8) -> Vec<PurchaseDetails>{
+        journal::record(&format!("broker {}", broker_no), Event::PositionsFlushed{broker_no});
+        flush_records(&mut PURCHASE_HISTORY.lock().unwrap(), broker_no)
+    }
+
+    // The stop under the price: the cut loss, raised by the trailing stop as the price climbs
+    pub fn stop_level(&self) -> f64{
+        self.trailing_stop.map_or(self.cut_loss, |t| t.level(self.high_water).max(self.cut_loss))
+    }
+
+    pub fn sell_reason(&self, current_stock_price: f64, now: i64) -> Option<SellReason>{
+        if current_stock_price <= self.cut_loss{
+            Some(SellReason::CutLoss)
+        }else if current_stock_price <= self.stop_level(){
+            Some(SellReason::TrailingStop)
+        }else if current_stock_price >= self.take_profit{
+            Some(SellReason::TakeProfit)
+        }else if self.expires_at.is_some_and(|at| now >= at){
+            Some(SellReason::Expired)
+        }else{
+            None
+        }
+    }
+
+    // Returns the records that hit their cut loss, trailing stop or take profit, to be sold
+    pub fn stock_sell_monitoring(stock_name:String, current_stock_price: f64,broker_no: i8)-> Vec<PurchaseDetails> {
+        sell_monitoring(broker_no, |name| (name == stock_name).then_some(current_stock_price)).into_iter().map(|(d, _)| d).collect()
+    }
+
+    // Every holding of the broker against the stock list, so expiry is checked even when
+    // the price doesn't move. Returns the records to be sold with their price
+    pub fn book_sell_monitoring(stock_list: &[Stock],broker_no: i8)-> Vec<(PurchaseDetails, f64)> {
+        sell_monitoring(broker_no, |name| iterate_stock_list(stock_list, name).map(|s| s.value))
+    }
+}
+
+fn sell_monitoring(broker_no: i8, price_of: impl Fn(&str) -> Option<f64>) -> Vec<(PurchaseDetails, f64)>{
+    let mut records = PURCHASE_HISTORY.lock().unwrap(); 
+    let now = now_millis();
+    let mut to_remove: Vec<(usize, f64)> = Vec::new();
+    let mut sold_stocks: Vec<(PurchaseDetails, f64)> = Vec::new();
+    for (index,d) in records.iter_mut().enumerate(){
+        if d.broker_no != broker_no{continue;}
+        let Some(current_stock_price) = price_of(&d.stock_name) else {continue};
+        if current_stock_price > d.high_water{
+            d.high_water = current_stock_price;
+            // only a trailing stop follows the high, the journal keeps its level rebuildable
+            if d.trailing_stop.is_some(){
+                journal::record(&format!("broker {}", broker_no), Event::HighWaterRaised{broker_no, user_id:d.id, stock_name:d.stock_name.clone(), high_water:d.high_water});
+            }
+        }
+        let Some(reason) = d.sell_reason(current_stock_price, now) else {continue};
+        let record = match reason{
+            SellReason::CutLoss => {
+                let loss_rate = format!("{:.2}",(((d.cut_loss - current_stock_price)/d.cut_loss) * 100.00));
+                logging::info(Component::Broker(broker_no), format!("Had sold User {}'s [{}] for cutting loss! [with ↓ {}%] - Price at: {}",
+                    d.id,d.stock_name,loss_rate,current_stock_price.round())).field("reason", "cut_loss").tone(Tone::Bad)
+            }
+            SellReason::TrailingStop => {
+                let drop_rate = format!("{:.2}",(((d.high_water - current_stock_price)/d.high_water) * 100.00));
+                logging::info(Component::Broker(broker_no), format!("Had sold User {}'s [{}] on its trailing stop! [↓ {}% from the high of {}] - Price at: {}",
+                    d.id,d.stock_name,drop_rate,d.high_water.round(),current_stock_price.round())).field("reason", "trailing_stop")
+                    .field("high_water", d.high_water).field("stop", d.stop_level()).tone(Tone::Bad)
+            }
+            SellReason::TakeProfit => {
+                let earn_rate = format!("{:.2}",(((current_stock_price - d.take_profit)/d.take_profit) * 100.00));
+                logging::info(Component::Broker(broker_no), format!("Had sold User {}'s [{}] for taking profit! [with ↑ {}%] - Price at: {}",
+                    d.id,d.stock_name,earn_rate,current_stock_price.round())).field("reason", "take_profit").tone(Tone::Good)
+            }
+            SellReason::Expired => {
+                logging::info(Component::Broker(broker_no), format!("Had sold User {}'s [{}] as the position expired - Price at: {}",
+                    d.id,d.stock_name,current_stock_price.round())).field("reason", "expired")
+            }
+        };
+        // the other legs of the bracket go with the holding
+        record.user(d.id).symbol(&d.stock_name).price(current_stock_price).field("quantity", d.num_stock).timed().emit();
+        journal::record(&format!("broker {}", broker_no), Event::SellTriggered{broker_no, user_id:d.id, stock_name:d.stock_name.clone(),
+            price:current_stock_price, num_stock:d.num_stock, reason});
+        to_remove.push((index, current_stock_price));
+    } 
+    // remove old records
+    for &(index, price) in to_remove.iter().rev() {
+        sold_stocks.push((records.remove(index), price));
+    }
+    sold_stocks
+}
+
+// The record keeping itself, shared with the journal's rebuild
+pub(crate) fn merge_order(records: &mut Vec<PurchaseDetails>, details: PurchaseDetails){
+    // only the same user's holding at this broker under the same bracket is topped up,
+    // shares bought with other levels keep a record of their own
+    match records.iter_mut().find(|d| d.broker_no == details.broker_no && d.id == details.id && d.stock_name == details.stock_name
+        && d.take_profit == details.take_profit && d.cut_loss == details.cut_loss && d.trailing_stop == details.trailing_stop
+        && d.high_water == details.high_water && d.expires_at == details.expires_at){
+        Some(d) => d.num_stock+=details.num_stock,
+        None => records.push(details),
+    }
+}
+
+pub(crate) fn flush_records(records: &mut Vec<PurchaseDetails>, broker_no: i8) -> Vec<PurchaseDetails>{
+    let (flushed, kept) = records.drain(..).partition(|d| d.broker_no == broker_no);
+    *records = kept;
+    flushed
+}
+
+pub(crate) fn reduce_records(records: &mut Vec<PurchaseDetails>, broker_no: i8, id: i8, stock_name: &str, num_stock: i128) -> Vec<PurchaseDetails>{
+    let mut reduced = 0;
+    let mut taken = Vec::new();
+    for d in records.iter_mut(){
+        if d.broker_no == broker_no && d.id == id && d.stock_name == stock_name && reduced < num_stock{
+            let qty = d.num_stock.min(num_stock - reduced);
+            d.num_stock-=qty;
+            reduced+=qty;
+            taken.push(PurchaseDetails{num_stock:qty, ..d.clone()});
+        }
+    }
+    records.retain(|d| d.num_stock > 0);
+    taken
+}
+
+pub(crate) fn raise_high_water(records: &mut [PurchaseDetails], broker_no: i8, id: i8, stock_name: &str, high_water: f64){
+    for d in records.iter_mut().filter(|d| d.broker_no == broker_no && d.id == id && d.stock_name == stock_name){
+        d.high_water = d.high_water.max(high_water);
+    }
+}
+
+// Every record of the holding gets the new levels, or none does
+pub(crate) fn amend_records(records: &mut [PurchaseDetails], broker_no: i8, id: i8, stock_name: &str, take_profit: Option<f64>, cut_loss: Option<f64>) -> Result<(), String>{
+    let mut holding: Vec<&mut PurchaseDetails> = records.iter_mut().filter(|d| d.broker_no == broker_no && d.id == id && d.stock_name == stock_name).collect();
+    if holding.is_empty(){return Err("no holding under watch".to_string());}
+    for d in holding.iter(){
+        let take_profit = take_profit.unwrap_or(d.take_profit);
+        let cut_loss = cut_loss.unwrap_or(d.cut_loss);
+        if !take_profit.is_finite() || !cut_loss.is_finite() || cut_loss < 0.0{return Err("levels must be non-negative prices".to_string());}
+        if cut_loss >= take_profit{return Err("cut loss must be below take profit".to_string());}
+    }
+    for d in holding.iter_mut(){
+        d.take_profit = take_profit.unwrap_or(d.take_profit);
+        d.cut_loss = cut_loss.unwrap_or(d.cut_loss);
+    }
+    Ok(())
+}
+
+// check whether the stock is listed on the exchange, price matching is left to the order book
+pub fn iterate_stock_list(stock_list: &[Stock],stock_name:&str)-> Option<Stock>{
+    for s in stock_list.iter(){
+        if s.name == stock_name{
+            return Some(s.clone())
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn holding(trailing_stop: Option<TrailingStop>, high_water: f64, expires_at: Option<i64>) -> PurchaseDetails{
+        PurchaseDetails{broker_no:1, id:3, stock_name:"AAPL".to_string(), take_profit:120.0, cut_loss:90.0, num_stock:10,
+            reply_to:"user_reports".to_string(), trailing_stop, high_water, expires_at}
+    }
+
+    #[test]
+    fn trailing_stops_parse_as_percent_or_amount(){
+        assert_eq!(TrailingStop::parse("5%"), Ok(TrailingStop::Percent(0.05)));
+        assert_eq!(TrailingStop::parse(" 2.5 "), Ok(TrailingStop::Amount(2.5)));
+        for spec in ["0%", "100%", "-1", "0", "abc", "%"]{
+            assert!(TrailingStop::parse(spec).is_err(), "{}", spec);
+        }
+        assert_eq!(TrailingStop::parse("5%").unwrap().to_string(), "5%");
+        assert_eq!(TrailingStop::Percent(0.1).level(200.0), 180.0);
+        assert_eq!(TrailingStop::Amount(2.5).level(100.0), 97.5);
+    }
+
+    #[test]
+    fn trailing_stop_never_goes_below_the_cut_loss(){
+        let d = holding(Some(TrailingStop::Percent(0.1)), 95.0, None);
+        assert_eq!(d.stop_level(), 90.0);
+        let d = holding(Some(TrailingStop::Percent(0.1)), 110.0, None);
+        assert_eq!(d.stop_level(), 99.0);
+        assert_eq!(holding(None, 110.0, None).stop_level(), 90.0);
+    }
+
+    #[test]
+    fn first_leg_to_trigger_is_the_reason(){
+        let d = holding(Some(TrailingStop::Amount(5.0)), 110.0, Some(1_000));
+        assert_eq!(d.sell_reason(89.0, 0), Some(SellReason::CutLoss));
+        assert_eq!(d.sell_reason(104.0, 0), Some(SellReason::TrailingStop));
+        assert_eq!(d.sell_reason(121.0, 0), Some(SellReason::TakeProfit));
+        assert_eq!(d.sell_reason(108.0, 0), None);
+        assert_eq!(d.sell_reason(108.0, 1_000), Some(SellReason::Expired));
+        assert_eq!(holding(None, 110.0, None).sell_reason(91.0, i64::MAX), None);
+    }
+
+    #[test]
+    fn reduce_returns_what_it_took_with_the_levels(){
+        let mut records = vec![holding(None, 100.0, None)];
+        let taken = reduce_records(&mut records, 1, 3, "AAPL", 4);
+        assert_eq!((taken.len(), taken[0].num_stock, taken[0].cut_loss, records[0].num_stock), (1, 4, 90.0, 6));
+        let taken = reduce_records(&mut records, 1, 3, "AAPL", 8);
+        assert_eq!(taken.iter().map(|d| d.num_stock).sum::<i128>(), 6);
+        assert!(records.is_empty());
+        // put back, as the broker does for a sell that didn't go
+        merge_order(&mut records, PurchaseDetails{num_stock:2, ..taken[0].clone()});
+        assert_eq!((records[0].num_stock, records[0].take_profit), (2, 120.0));
+    }
+
+    #[test]
+    fn high_water_only_rises(){
+        let mut records = vec![holding(Some(TrailingStop::Percent(0.05)), 100.0, None)];
+        raise_high_water(&mut records, 1, 3, "AAPL", 104.0);
+        raise_high_water(&mut records, 1, 3, "AAPL", 101.0);
+        raise_high_water(&mut records, 2, 3, "AAPL", 150.0);
+        assert_eq!(records[0].high_water, 104.0);
+    }
+
+    #[test]
+    fn buys_with_other_brackets_keep_their_levels(){
+        let mut records = Vec::new();
+        merge_order(&mut records, holding(None, 100.0, None));
+        merge_order(&mut records, PurchaseDetails{take_profit:130.0, cut_loss:95.0, num_stock:5, ..holding(None, 100.0, None)});
+        merge_order(&mut records, PurchaseDetails{num_stock:2, ..holding(Some(TrailingStop::Percent(0.05)), 104.0, None)});
+        // the same bracket again only tops up
+        merge_order(&mut records, holding(None, 100.0, None));
+        let levels: Vec<(f64, f64, i128)> = records.iter().map(|d| (d.take_profit, d.cut_loss, d.num_stock)).collect();
+        assert_eq!(levels, vec![(120.0, 90.0, 20), (130.0, 95.0, 5), (120.0, 90.0, 2)]);
+        assert_eq!((records[2].trailing_stop, records[2].high_water), (Some(TrailingStop::Percent(0.05)), 104.0));
+
+        // an amendment covers the whole holding, or none of it if a record can't take it
+        assert!(amend_records(&mut records, 1, 3, "AAPL", None, Some(125.0)).is_err());
+        assert_eq!(records[1].cut_loss, 95.0);
+        amend_records(&mut records, 1, 3, "AAPL", Some(140.0), None).unwrap();
+        assert!(records.iter().all(|d| d.take_profit == 140.0));
+        assert_eq!((records[0].cut_loss, records[1].cut_loss), (90.0, 95.0));
+    }
+}
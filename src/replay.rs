@@ -0,0 +1,93 @@
+// Re-drive brokers from a recorded journal: the market data, fills and order notices the
+// exchange broadcast and the users' orders, cancels and amends each broker received are published again,
+// in the same order and spaced like the original run (or faster), so a broker's
+// `stock_sell_monitoring` decisions can be reproduced without the exchange or users.
+use std::{collections::BTreeSet, thread, time::{Duration, Instant}};
+use crate::config::Config;
+use crate::exchange::HOUSE_BROKER;
+use crate::journal::{Event, JournalEntry};
+use crate::logging::{self, Component};
+use crate::message::{BrokerControl, EndOfSession, MarketData};
+use crate::order::now_millis;
+use crate::session;
+use crate::transport::{Result, Transport};
+
+// How long to wait for the brokers to register before replaying anyway
+pub const REGISTER_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Brokers that received orders or fills in the journal
+pub fn brokers_in(entries: &[JournalEntry]) -> BTreeSet<i8>{
+    entries.iter().filter_map(|entry| match &entry.event{
+        Event::OrderReceived{broker_no, ..} | Event::OrderControlReceived{broker_no, ..} => Some(*broker_no),
+        Event::Fill{execution} if execution.broker_no != HOUSE_BROKER => Some(execution.broker_no),
+        _ => None,
+    }).collect()
+}
+
+// Wait until every broker has registered, they only get broadcasts once they've subscribed
+fn wait_for_brokers(transport: &dyn Transport, config: &Config, brokers: &BTreeSet<i8>) -> Result<()>{
+    let registry = transport.consume(&config.queues.registry)?;
+    let mut waiting = brokers.clone();
+    let started = Instant::now();
+    while !waiting.is_empty(){
+        let Some(left) = REGISTER_TIMEOUT.checked_sub(started.elapsed()) else {
+            logging::warn(Component::Named("Replay"), format!("Broker(s) {:?} didn't register, replaying anyway", waiting)).field("brokers", &waiting).emit();
+            break;
+        };
+        let Some(body) = registry.recv_timeout(left)? else {continue};
+        if let Ok(BrokerControl::Register{broker_no}) = serde_json::from_slice(&body){
+            if waiting.remove(&broker_no){logging::info(Component::Named("Replay"), format!("Broker {} registered", broker_no)).field("broker", broker_no).emit();}
+        }
+    }
+    Ok(())
+}
+
+// Publish the entries' broker-facing messages. `speed` divides the original gaps
+// between them, f64::INFINITY sends them back to back. Orders only go to `brokers`.
+pub fn run(transport: &dyn Transport, config: &Config, entries: &[JournalEntry], speed: f64, brokers: &BTreeSet<i8>) -> Result<()>{
+    let queues = &config.queues;
+    wait_for_brokers(transport, config, brokers)?;
+    let started = Instant::now();
+    let first = entries.first().map(|entry| entry.timestamp).unwrap_or(0);
+    let mut sent = 0;
+    let mut ended = false;
+    for entry in entries{
+        if session::stop_requested(){
+            logging::info(Component::Named("Replay"), "Interrupted").emit();
+            break;
+        }
+        let (queue, body, fanout) = match &entry.event{
+            Event::MarketDataPublished{message} => {
+                let queue = match message{MarketData::Update{..} => &queues.stock_trending, _ => &queues.stock_info};
+                (queue.clone(), serde_json::to_vec(message), true)
+            }
+            Event::Fill{execution} => (queues.executions.clone(), serde_json::to_vec(execution), true),
+            Event::OrderNotice{notice} => (queues.order_notices.clone(), serde_json::to_vec(notice), true),
+            Event::SessionEnded{reason} => {
+                ended = true;
+                (queues.session.clone(), serde_json::to_vec(&EndOfSession{reason:reason.clone(), timestamp:now_millis()}), true)
+            }
+            Event::OrderReceived{broker_no, order} if brokers.contains(broker_no) => (queues.orders_for(*broker_no), serde_json::to_vec(order), false),
+            Event::OrderControlReceived{broker_no, control} if brokers.contains(broker_no) => (queues.orders_for(*broker_no), serde_json::to_vec(control), false),
+            _ => continue,
+        };
+        // keep the original spacing, measured from the start so sleeps don't drift
+        let due = Duration::from_secs_f64((entry.timestamp - first).max(0) as f64 / 1000.0 / speed);
+        if let Some(wait) = due.checked_sub(started.elapsed()){thread::sleep(wait);}
+        let body = body.expect("Failed to serialize");
+        if fanout{
+            transport.broadcast(&queue, &body)?;
+        }else{
+            transport.publish(&queue, &body)?;
+        }
+        sent+=1;
+    }
+    let elapsed_s = started.elapsed().as_secs_f64();
+    logging::info(Component::Named("Replay"), format!("Published {} messages in {:.1}s", sent, elapsed_s)).field("sent", sent).field("elapsed_s", elapsed_s).emit();
+    // the brokers only stop at the end of a session, journals cut short don't have one
+    if !ended{
+        let end = EndOfSession{reason:"end of replay".to_string(), timestamp:now_millis()};
+        transport.broadcast(&queues.session, &serde_json::to_vec(&end).expect("Failed to serialize"))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,170 @@
+// Pre-trade risk checks. Every new order a broker gets from a user, replacements
+// included, is checked against these limits before it's accepted and sent on;
+// the broker's own sells of the holdings it watches only reduce risk and skip them.
+use std::{collections::{HashMap, VecDeque}, sync::Mutex};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use crate::message::{OrderType, Side};
+use crate::Order;
+
+// A limit of 0 is off
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskConfig{
+    pub max_order_qty: i64, // shares in one order
+    pub max_notional: f64, // price × shares of one order
+    pub max_position: i64, // shares of one stock a user may be long or short, working orders included
+    pub max_gross_exposure: f64, // market value of a user's longs and shorts plus their working orders
+    pub price_band_pct: f64, // how far an order's price or stop may be from the listed price
+    pub max_orders: u32, // orders a user may send one broker per rate_window_ms
+    pub rate_window_ms: u64,
+}
+
+impl Default for RiskConfig{
+    fn default() -> Self {
+        RiskConfig{max_order_qty:10_000, max_notional:100_000.0, max_position:1_000, max_gross_exposure:500_000.0,
+            price_band_pct:0.25, max_orders:20, rate_window_ms:1000}
+    }
+}
+
+impl RiskConfig{
+    pub fn validate(&self) -> Result<(), String>{
+        for (name, limit) in [("max_order_qty", self.max_order_qty as f64), ("max_notional", self.max_notional), ("max_position", self.max_position as f64),
+            ("max_gross_exposure", self.max_gross_exposure), ("price_band_pct", self.price_band_pct)]{
+            if !limit.is_finite() || limit < 0.0{return Err(format!("{} can't be negative", name));}
+        }
+        if self.max_orders > 0 && self.rate_window_ms == 0{return Err("rate_window_ms must be positive when max_orders is set".to_string());}
+        Ok(())
+    }
+}
+
+// What a user already has on, for the position & exposure limits
+pub struct Exposure{
+    pub position: i128, // shares of the order's stock, negative when short
+    pub gross: f64,
+}
+
+lazy_static!{
+    // when each user's latest orders reached each broker, by (broker, user)
+    static ref ORDER_TIMES: Mutex<HashMap<(i8, i8), VecDeque<i64>>> = Mutex::new(HashMap::new());
+}
+
+// Count an order towards the user's rate at the broker. Brokers do this before any
+// other check, so every order counts, refused ones too
+pub fn throttle(limits: &RiskConfig, broker_no: i8, user_id: i8, now: i64) -> Result<(), &'static str>{
+    if limits.max_orders > 0 && over_rate(limits, broker_no, user_id, now){return Err("order rate limit exceeded");}
+    Ok(())
+}
+
+// The most an order can trade at per share: its limit, or for a market order (and a stop
+// that triggers into one) the price band above the higher of the listed, seen and stop prices.
+// A buy's cash is held at this price. With the band off a market order may still fill beyond it
+pub fn worst_price(limits: &RiskConfig, order: &Order, listed: f64) -> f64{
+    match order.order_type{
+        OrderType::Limit | OrderType::StopLimit => order.bid_price,
+        OrderType::Market | OrderType::Stop => {
+            order.bid_price.max(listed).max(order.stop_price.unwrap_or_default()) * (1.0 + limits.price_band_pct)
+        }
+    }
+}
+
+// The first limit an order breaks. `listed` is the stock's last listed price, orders
+// that bring a position back towards 0 pass the position & exposure limits
+pub fn check(limits: &RiskConfig, order: &Order, listed: f64, exposure: &Exposure) -> Result<(), &'static str>{
+    if limits.max_order_qty > 0 && order.num_stock > limits.max_order_qty.into(){return Err("max order size exceeded");}
+    if limits.max_notional > 0.0 && worst_price(limits, order, listed) * order.num_stock as f64 > limits.max_notional{return Err("max notional exceeded");}
+    if limits.price_band_pct > 0.0{
+        let off_band = |price: f64| (price / listed - 1.0).abs() > limits.price_band_pct;
+        if off_band(order.bid_price) || order.stop_price.is_some_and(off_band){return Err("price outside the band around the listed price");}
+    }
+    let signed = match order.side{Side::Buy => order.num_stock, Side::Sell => -order.num_stock};
+    let projected = exposure.position + signed;
+    let added = projected.abs() - exposure.position.abs();
+    if added <= 0{return Ok(());}
+    if limits.max_position > 0 && projected.abs() > limits.max_position.into(){return Err("max position exceeded");}
+    if limits.max_gross_exposure > 0.0 && exposure.gross + added as f64 * listed > limits.max_gross_exposure{return Err("max gross exposure exceeded");}
+    Ok(())
+}
+
+fn over_rate(limits: &RiskConfig, broker_no: i8, user_id: i8, now: i64) -> bool{
+    let mut times = ORDER_TIMES.lock().unwrap();
+    let recent = times.entry((broker_no, user_id)).or_default();
+    while recent.front().is_some_and(|&at| now - at >= limits.rate_window_ms as i64){recent.pop_front();}
+    recent.push_back(now);
+    recent.len() > limits.max_orders as usize
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::message::TimeInForce;
+
+    fn order(side: Side, price: f64, num_stock: i128) -> Order{
+        Order{order_id:"U1-1".to_string(), client_id:1, stock_name:"AAPL".to_string(), side, bid_price:price, take_profit:0.0, cut_loss:0.0,
+            num_stock, timestamp:0, reply_to:"user_reports".to_string(), short:false, trailing_stop:None, expire_after_ms:None,
+            order_type:OrderType::Limit, time_in_force:TimeInForce::Gtc, stop_price:None}
+    }
+
+    fn flat() -> Exposure{
+        Exposure{position:0, gross:0.0}
+    }
+
+    fn limits() -> RiskConfig{
+        RiskConfig{max_order_qty:100, max_notional:5_000.0, max_position:150, max_gross_exposure:20_000.0,
+            price_band_pct:0.1, max_orders:3, rate_window_ms:1000}
+    }
+
+    #[test]
+    fn each_limit_has_its_reason(){
+        let limits = limits();
+        assert_eq!(check(&limits, &order(Side::Buy, 100.0, 40), 100.0, &flat()), Ok(()));
+        assert_eq!(check(&limits, &order(Side::Buy, 10.0, 101), 10.0, &flat()), Err("max order size exceeded"));
+        assert_eq!(check(&limits, &order(Side::Buy, 100.0, 60), 100.0, &flat()), Err("max notional exceeded"));
+        assert_eq!(check(&limits, &order(Side::Buy, 111.0, 1), 100.0, &flat()), Err("price outside the band around the listed price"));
+        let stop = Order{stop_price:Some(80.0), order_type:OrderType::Stop, ..order(Side::Sell, 100.0, 1)};
+        assert_eq!(check(&limits, &stop, 100.0, &flat()), Err("price outside the band around the listed price"));
+        assert_eq!(check(&limits, &order(Side::Buy, 10.0, 60), 10.0, &Exposure{position:100, gross:0.0}), Err("max position exceeded"));
+        assert_eq!(check(&limits, &order(Side::Buy, 100.0, 10), 100.0, &Exposure{position:0, gross:19_500.0}), Err("max gross exposure exceeded"));
+    }
+
+    #[test]
+    fn market_orders_count_their_worst_price(){
+        let limits = limits();
+        let market = Order{order_type:OrderType::Market, ..order(Side::Buy, 100.0, 46)};
+        assert!((worst_price(&limits, &market, 100.0) - 110.0).abs() < 1e-9);
+        assert!((worst_price(&limits, &Order{stop_price:Some(105.0), order_type:OrderType::Stop, ..market.clone()}, 100.0) - 115.5).abs() < 1e-9);
+        assert_eq!(worst_price(&limits, &order(Side::Buy, 100.0, 46), 120.0), 100.0);
+        // 4,600 at the limit, but up to 5,060 with the band, what the broker would hold
+        assert_eq!(check(&limits, &order(Side::Buy, 100.0, 46), 100.0, &flat()), Ok(()));
+        assert_eq!(check(&limits, &market, 100.0, &flat()), Err("max notional exceeded"));
+    }
+
+    #[test]
+    fn reducing_a_position_passes_the_position_limits(){
+        let limits = limits();
+        let long = Exposure{position:200, gross:50_000.0};
+        assert_eq!(check(&limits, &order(Side::Sell, 100.0, 50), 100.0, &long), Ok(()));
+        // selling past flat opens a short, which counts
+        assert_eq!(check(&limits, &order(Side::Sell, 10.0, 100), 10.0, &Exposure{position:-60, gross:0.0}), Err("max position exceeded"));
+        assert_eq!(check(&limits, &order(Side::Sell, 10.0, 100), 10.0, &Exposure{position:40, gross:0.0}), Ok(()));
+    }
+
+    #[test]
+    fn zero_turns_a_limit_off(){
+        let off = RiskConfig{max_order_qty:0, max_notional:0.0, max_position:0, max_gross_exposure:0.0, price_band_pct:0.0, max_orders:0, rate_window_ms:0};
+        assert_eq!(check(&off, &order(Side::Buy, 500.0, 1_000_000), 1.0, &flat()), Ok(()));
+        for _ in 0..10{assert_eq!(throttle(&off, 9, 1, 0), Ok(()));}
+    }
+
+    #[test]
+    fn rate_window_slides(){
+        let limits = limits();
+        // its own broker, the window is shared by every test
+        for now in [0, 100, 200]{assert_eq!(throttle(&limits, 7, 1, now), Ok(()));}
+        assert_eq!(throttle(&limits, 7, 1, 300), Err("order rate limit exceeded"));
+        assert_eq!(throttle(&limits, 7, 2, 300), Ok(()));
+        // the first two have left the window, the refused one still counts
+        assert_eq!(throttle(&limits, 7, 1, 1_150), Ok(()));
+        assert_eq!(throttle(&limits, 7, 1, 1_160), Err("order rate limit exceeded"));
+    }
+}
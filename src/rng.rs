@@ -0,0 +1,40 @@
+use rand::{rngs::StdRng, SeedableRng};
+
+// Every component that draws random numbers gets its own generator, derived
+// from the run's seed. The threads never share one, so the draws of a
+// component don't depend on how the threads happen to interleave.
+pub const EXCHANGE_STREAM: u64 = 1;
+pub const USERS_STREAM: u64 = 2;
+pub const PRICE_MODEL_STREAM: u64 = 3;
+
+pub fn component_rng(seed: u64, stream: u64) -> StdRng{
+    StdRng::seed_from_u64(seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+}
+
+// Seed for runs started without `--seed`, printed so they can be replayed
+pub fn random_seed() -> u64{
+    rand::random()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use rand::Rng;
+
+    fn draws(seed: u64, stream: u64) -> Vec<u64>{
+        let mut rng = component_rng(seed, stream);
+        (0..8).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn same_seed_same_stream(){
+        assert_eq!(draws(42, USERS_STREAM), draws(42, USERS_STREAM));
+    }
+
+    #[test]
+    fn components_and_seeds_get_their_own_streams(){
+        assert_ne!(draws(42, EXCHANGE_STREAM), draws(42, USERS_STREAM));
+        assert_ne!(draws(42, USERS_STREAM), draws(42, PRICE_MODEL_STREAM));
+        assert_ne!(draws(42, EXCHANGE_STREAM), draws(43, EXCHANGE_STREAM));
+    }
+}
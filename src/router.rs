@@ -0,0 +1,222 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::message::{BrokerStatus, Side};
+use crate::Order;
+
+// How the users' orders are spread over the brokers
+#[derive(Clone,Copy,Debug,Default,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingPolicy{
+    #[default]
+    RoundRobin,
+    LeastOutstanding,
+    LowestCommission,
+    BestPrice, // best all-in price per share, commission and markup included
+}
+
+impl RoutingPolicy{
+    pub fn parse(name: &str) -> Result<RoutingPolicy, String>{
+        match name{
+            "round_robin" => Ok(RoutingPolicy::RoundRobin),
+            "least_outstanding" => Ok(RoutingPolicy::LeastOutstanding),
+            "lowest_commission" => Ok(RoutingPolicy::LowestCommission),
+            "best_price" => Ok(RoutingPolicy::BestPrice),
+            other => Err(format!("unknown routing policy '{}'", other)),
+        }
+    }
+}
+
+impl fmt::Display for RoutingPolicy{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self{
+            RoutingPolicy::RoundRobin => "round robin",
+            RoutingPolicy::LeastOutstanding => "least outstanding orders",
+            RoutingPolicy::LowestCommission => "lowest commission",
+            RoutingPolicy::BestPrice => "best quoted price",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// What a broker charges, per share
+#[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerTerms{
+    pub broker_no: i8,
+    pub commission: f64, // flat amount per share
+    pub markup: f64, // fraction of the price
+}
+
+impl Default for BrokerTerms{
+    fn default() -> Self {
+        BrokerTerms{broker_no:0, commission:0.01, markup:0.0}
+    }
+}
+
+impl BrokerTerms{
+    pub fn fee(&self, price: f64, num_stock: i128) -> f64{
+        (price * self.markup + self.commission) * num_stock as f64
+    }
+
+    // All-in price per share the user pays (buy) or gets (sell)
+    pub fn quote(&self, side: Side, price: f64) -> f64{
+        match side{
+            Side::Buy => price * (1.0 + self.markup) + self.commission,
+            Side::Sell => price * (1.0 - self.markup) - self.commission,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String>{
+        if !self.commission.is_finite() || self.commission < 0.0{return Err("commission can't be negative".to_string());}
+        if !(0.0..1.0).contains(&self.markup){return Err("markup must be in [0, 1)".to_string());}
+        Ok(())
+    }
+}
+
+// Picks a broker for each order from the statuses the brokers broadcast,
+// and remembers which broker got which order. Ties go to the lowest broker number
+pub struct OrderRouter{
+    policy: RoutingPolicy,
+    brokers: BTreeMap<i8, BrokerStatus>,
+    next: usize, // round robin position
+    handled: HashMap<String, i8>, // order id -> broker
+}
+
+impl OrderRouter{
+    pub fn new(policy: RoutingPolicy) -> OrderRouter{
+        OrderRouter{policy, brokers: BTreeMap::new(), next: 0, handled: HashMap::new()}
+    }
+
+    pub fn policy(&self) -> RoutingPolicy{
+        self.policy
+    }
+
+    pub fn update(&mut self, status: BrokerStatus){
+        if status.active{
+            self.brokers.insert(status.broker_no, status);
+        }else{
+            self.brokers.remove(&status.broker_no);
+        }
+    }
+
+    pub fn has_brokers(&self) -> bool{
+        !self.brokers.is_empty()
+    }
+
+    pub fn route(&mut self, order: &Order) -> Option<i8>{
+        let brokers: Vec<&BrokerStatus> = self.brokers.values().collect();
+        let chosen = match self.policy{
+            RoutingPolicy::RoundRobin => {
+                let broker = brokers.get(self.next % brokers.len().max(1)).copied();
+                self.next+=1;
+                broker
+            }
+            RoutingPolicy::LeastOutstanding => brokers.into_iter().min_by_key(|b| b.outstanding),
+            RoutingPolicy::LowestCommission => brokers.into_iter().min_by(|a, b| a.terms.commission.total_cmp(&b.terms.commission)),
+            RoutingPolicy::BestPrice => {
+                let quote = |b: &BrokerStatus| b.terms.quote(order.side, order.bid_price);
+                match order.side{
+                    Side::Buy => brokers.into_iter().min_by(|a, b| quote(a).total_cmp(&quote(b))),
+                    Side::Sell => brokers.into_iter().min_by(|a, b| quote(b).total_cmp(&quote(a))),
+                }
+            }
+        }?.broker_no;
+        self.assign(&order.order_id, chosen);
+        Some(chosen)
+    }
+
+    // Record an order sent to a given broker, e.g. a sell where the shares are held
+    pub fn assign(&mut self, order_id: &str, broker_no: i8){
+        // counts until the broker's next status
+        if let Some(status) = self.brokers.get_mut(&broker_no){status.outstanding+=1;}
+        self.handled.insert(order_id.to_string(), broker_no);
+    }
+
+    pub fn handled_by(&self, order_id: &str) -> Option<i8>{
+        self.handled.get(order_id).copied()
+    }
+
+    // Orders routed to each broker so far
+    pub fn routed_counts(&self) -> BTreeMap<i8, usize>{
+        let mut counts = BTreeMap::new();
+        for broker_no in self.handled.values(){
+            *counts.entry(*broker_no).or_default()+=1;
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::message::{OrderType, TimeInForce};
+
+    fn status(broker_no: i8, commission: f64, markup: f64, outstanding: usize) -> BrokerStatus{
+        BrokerStatus{broker_no, terms:BrokerTerms{broker_no, commission, markup}, outstanding, active:true}
+    }
+
+    fn order(id: &str, side: Side, price: f64) -> Order{
+        Order{order_id:id.to_string(), client_id:1, stock_name:"apl".to_string(), side, bid_price:price, take_profit:0.0, cut_loss:0.0,
+            num_stock:10, timestamp:0, reply_to:"userReports".to_string(), short:false, trailing_stop:None, expire_after_ms:None,
+            order_type:OrderType::Limit, time_in_force:TimeInForce::Gtc, stop_price:None}
+    }
+
+    fn router(policy: RoutingPolicy, statuses: Vec<BrokerStatus>) -> OrderRouter{
+        let mut router = OrderRouter::new(policy);
+        for status in statuses{router.update(status);}
+        router
+    }
+
+    #[test]
+    fn fee_and_quote_include_commission_and_markup(){
+        let terms = BrokerTerms{broker_no:1, commission:0.02, markup:0.001};
+        assert!((terms.fee(100.0, 10) - 1.2).abs() < 1e-9);
+        assert!((terms.quote(Side::Buy, 100.0) - 100.12).abs() < 1e-9);
+        assert!((terms.quote(Side::Sell, 100.0) - 99.88).abs() < 1e-9);
+    }
+
+    #[test]
+    fn round_robin_takes_turns(){
+        let mut router = router(RoutingPolicy::RoundRobin, vec![status(2, 0.0, 0.0, 0), status(1, 0.0, 0.0, 0)]);
+        let chosen: Vec<i8> = (0..3).map(|i| router.route(&order(&format!("U1-{}", i), Side::Buy, 100.0)).unwrap()).collect();
+        assert_eq!(chosen, vec![1, 2, 1]);
+        assert_eq!(router.handled_by("U1-1"), Some(2));
+        assert_eq!(router.routed_counts(), BTreeMap::from([(1, 2), (2, 1)]));
+    }
+
+    #[test]
+    fn least_outstanding_counts_its_own_orders(){
+        let mut router = router(RoutingPolicy::LeastOutstanding, vec![status(1, 0.0, 0.0, 3), status(2, 0.0, 0.0, 1), status(3, 0.0, 0.0, 2)]);
+        assert_eq!(router.route(&order("U1-1", Side::Buy, 100.0)), Some(2));
+        // 2 now has 2 like 3, the tie goes to the lower number
+        assert_eq!(router.route(&order("U1-2", Side::Buy, 100.0)), Some(2));
+        assert_eq!(router.route(&order("U1-3", Side::Buy, 100.0)), Some(3));
+    }
+
+    #[test]
+    fn lowest_commission_ties_go_to_the_lowest_broker(){
+        let mut router = router(RoutingPolicy::LowestCommission, vec![status(1, 0.02, 0.0, 0), status(2, 0.01, 0.5, 0), status(3, 0.01, 0.0, 0)]);
+        assert_eq!(router.route(&order("U1-1", Side::Buy, 100.0)), Some(2));
+    }
+
+    #[test]
+    fn best_price_depends_on_the_side_and_price(){
+        // 1: flat 0.05 a share, 2: 0.1% of the price
+        let mut router = router(RoutingPolicy::BestPrice, vec![status(1, 0.05, 0.0, 0), status(2, 0.0, 0.001, 0)]);
+        assert_eq!(router.route(&order("U1-1", Side::Buy, 10.0)), Some(2));
+        assert_eq!(router.route(&order("U1-2", Side::Buy, 100.0)), Some(1));
+        assert_eq!(router.route(&order("U1-3", Side::Sell, 10.0)), Some(2));
+        // 0.05 either way at 50
+        assert_eq!(router.route(&order("U1-4", Side::Sell, 50.0)), Some(1));
+        assert_eq!(router.route(&order("U1-5", Side::Buy, 50.0)), Some(1));
+    }
+
+    #[test]
+    fn stopped_brokers_leave_the_routing(){
+        let mut router = router(RoutingPolicy::RoundRobin, vec![status(1, 0.0, 0.0, 0)]);
+        router.update(BrokerStatus{active:false, ..status(1, 0.0, 0.0, 0)});
+        assert!(!router.has_brokers());
+        assert_eq!(router.route(&order("U1-1", Side::Buy, 100.0)), None);
+    }
+}
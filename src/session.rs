@@ -0,0 +1,118 @@
+// How a run ends. The exchange closes the session once every user is done and a
+// round passes without orders, or when the process is interrupted, and broadcasts
+// `EndOfSession`. The brokers then cancel what's still working, flush their open
+// positions, publish a `FinalReport` and deregister, and the exchange waits for them.
+use std::{process, sync::{atomic::{AtomicBool, Ordering}, Condvar, Mutex}};
+use crate::logging::{self, Component};
+
+// Set by Ctrl-C or SIGTERM, the whole process winds down
+static STOP: AtomicBool = AtomicBool::new(false);
+
+// Ctrl-C or SIGTERM asks every thread to stop, a second one exits straight away
+pub fn install_signal_handler(){
+    ctrlc::set_handler(||{
+        if STOP.swap(true, Ordering::SeqCst){
+            logging::warn(Component::Named("Signal"), "Interrupted again, exiting without draining").emit();
+            process::exit(130);
+        }
+        logging::warn(Component::Named("Signal"), "Interrupted, shutting down (press Ctrl-C again to exit now)..").emit();
+    }).expect("Failed to set the Ctrl-C handler");
+}
+
+pub fn stop_requested() -> bool{
+    STOP.load(Ordering::SeqCst)
+}
+
+#[derive(Default)]
+struct State{
+    visited: i32, // users that had placed their orders
+    users_done: bool,
+    ended: bool,
+}
+
+// Progress of the exchange & users threads of one process
+#[derive(Default)]
+pub struct Session{
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Session{
+    pub fn user_visited(&self){
+        self.state.lock().unwrap().visited+=1;
+    }
+
+    pub fn visited(&self) -> i32{
+        self.state.lock().unwrap().visited
+    }
+
+    // The users are gone and won't send any more orders
+    pub fn finish_users(&self){
+        self.state.lock().unwrap().users_done = true;
+        self.changed.notify_all();
+    }
+
+    pub fn users_done(&self) -> bool{
+        self.state.lock().unwrap().users_done
+    }
+
+    pub fn end(&self){
+        self.state.lock().unwrap().ended = true;
+        self.changed.notify_all();
+    }
+
+    pub fn ended(&self) -> bool{
+        self.state.lock().unwrap().ended
+    }
+
+    // Ends the session once the returned guard drops, even if the exchange's thread panics
+    pub fn end_on_drop(&self) -> Guard<'_>{
+        Guard{session:self, users:false}
+    }
+
+    // Finishes the users once the returned guard drops, even if their thread panics
+    pub fn finish_users_on_drop(&self) -> Guard<'_>{
+        Guard{session:self, users:true}
+    }
+
+    // Block until the exchange closed the session and the users are done
+    pub fn wait(&self){
+        let mut state = self.state.lock().unwrap();
+        while !(state.ended && state.users_done){
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+// So `wait` can't block forever on a thread that didn't get to the end
+pub struct Guard<'a>{
+    session: &'a Session,
+    users: bool,
+}
+
+impl Drop for Guard<'_>{
+    fn drop(&mut self){
+        if self.users{self.session.finish_users();}else{self.session.end();}
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn panicking_threads_still_end_the_session(){
+        let session = Arc::new(Session::default());
+        let handles: Vec<_> = [false, true].into_iter().map(|users| {
+            let session = Arc::clone(&session);
+            thread::spawn(move || {
+                let _guard = if users{session.finish_users_on_drop()}else{session.end_on_drop()};
+                panic!("bad message");
+            })
+        }).collect();
+        for handle in handles{assert!(handle.join().is_err());}
+        session.wait();
+        assert!(session.ended() && session.users_done());
+    }
+}
@@ -0,0 +1,530 @@
+// How a simulated user trades. A strategy sees every stock list the users get
+// and the execution reports of its own orders, and decides what to order when
+// its user visits the stock list page and before the user leaves. Through the
+// context it can also cancel or replace its working orders and amend the levels
+// its brokers watch on its holdings.
+use std::{collections::{HashMap, VecDeque}, fmt};
+use rand::{rngs::StdRng, Rng};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use crate::message::{ExecutionReport, OrderType, Side, TimeInForce};
+use crate::orderbook::to_ticks;
+use crate::purchase::TrailingStop;
+use crate::users::UserBehaviour;
+use crate::Stock;
+
+// An order a strategy wants placed, the users thread numbers and routes it
+#[derive(Clone,Debug)]
+pub struct Intent{
+    pub stock_name: String,
+    pub side: Side,
+    pub short: bool,
+    pub price: f64, // limit price
+    pub num_stock: i128,
+    pub take_profit: f64,
+    pub cut_loss: f64,
+    pub trailing_stop: Option<TrailingStop>,
+    pub expire_after_ms: Option<i64>,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub stop_price: Option<f64>,
+    pub broker: Option<i8>, // sells of held shares go through the broker holding them
+}
+
+impl Intent{
+    // A GTC limit order at the current price, levels at the price and no other exits
+    pub fn new(stock: &Stock, side: Side, num_stock: i128) -> Intent{
+        Intent{stock_name:stock.name.clone(), side, short:false, price:stock.value, num_stock, take_profit:stock.value, cut_loss:stock.value,
+            trailing_stop:None, expire_after_ms:None, order_type:OrderType::Limit, time_in_force:TimeInForce::Gtc, stop_price:None, broker:None}
+    }
+
+    // The user's order type & time in force. Stops sit stop_offset_pct beyond the price, a stop-limit's limit at the stop
+    pub fn typed(mut self, behaviour: &UserBehaviour) -> Intent{
+        self.order_type = behaviour.order_type;
+        self.time_in_force = behaviour.time_in_force;
+        if self.order_type.is_stop(){
+            let stop_price = match self.side{
+                Side::Buy => self.price * (1.0 + behaviour.stop_offset_pct),
+                Side::Sell => self.price * (1.0 - behaviour.stop_offset_pct),
+            };
+            self.stop_price = Some(stop_price);
+            self.price = stop_price;
+        }
+        self
+    }
+}
+
+// One of the user's orders still working, as its reports left it
+#[derive(Clone,Debug)]
+pub struct OpenOrder{
+    pub order_id: String,
+    pub stock_name: String,
+    pub side: Side,
+    pub price: f64,
+    pub leaves_qty: i128,
+    pub broker: i8,
+}
+
+// A change to an order already sent, or to a holding's levels at its broker
+#[derive(Clone,Debug)]
+pub enum Request{
+    Cancel{order_id:String},
+    Replace{order_id:String, intent:Intent}, // same stock & side, what's left of the order is cancelled
+    AmendLevels{stock_name:String, broker:i8, take_profit:Option<f64>, cut_loss:Option<f64>}, // None keeps a level
+}
+
+// What a strategy can look at when it decides
+pub struct Context<'a>{
+    pub user: i8,
+    pub stocks: &'a [Stock], // the stock list on the page
+    pub holdings: Vec<(String, i8, i128)>, // (stock, broker, shares) the user can sell
+    pub orders: Vec<OpenOrder>, // the user's working orders
+    pub behaviour: &'a UserBehaviour,
+    pub rng: &'a mut StdRng,
+    pub requests: Vec<Request>, // sent before the intents
+}
+
+impl Context<'_>{
+    pub fn cancel(&mut self, order_id: &str){
+        self.requests.push(Request::Cancel{order_id:order_id.to_string()});
+    }
+
+    pub fn replace(&mut self, order_id: &str, intent: Intent){
+        self.requests.push(Request::Replace{order_id:order_id.to_string(), intent});
+    }
+
+    pub fn amend_levels(&mut self, stock_name: &str, broker: i8, take_profit: Option<f64>, cut_loss: Option<f64>){
+        self.requests.push(Request::AmendLevels{stock_name:stock_name.to_string(), broker, take_profit, cut_loss});
+    }
+}
+
+pub trait Strategy: Send{
+    fn name(&self) -> String;
+    // Every stock list the users see, in order
+    fn on_market_data(&mut self, _stocks: &[Stock]){}
+    // Execution reports of the user's own orders
+    fn on_fill(&mut self, _report: &ExecutionReport){}
+    // Orders for a visit to the stock list page
+    fn on_visit(&mut self, ctx: &mut Context) -> Vec<Intent>;
+    // Orders before the user leaves
+    fn on_exit(&mut self, _ctx: &mut Context) -> Vec<Intent>{Vec::new()}
+}
+
+#[derive(Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum StrategyConfig{
+    #[default]
+    Random, // random stocks at the current price, the original users
+    Momentum{lookback:usize, threshold:f64}, // buy what rose more than threshold over lookback stock lists
+    MeanReversion{lookback:usize, threshold:f64}, // buy what fell more than threshold below its mean
+    BuyAndHold, // one basket on the first visit, never sold
+    MarketMaker{spread:f64, qty:i64}, // bid below and offer held shares above the price
+}
+
+impl StrategyConfig{
+    // "random", "momentum[:lookback[:threshold]]", "mean_reversion[:lookback[:threshold]]",
+    // "buy_and_hold" or "market_maker[:spread[:qty]]", missing parameters take the defaults
+    pub fn parse(spec: &str) -> Result<StrategyConfig, String>{
+        let mut parts = spec.split(':');
+        let name = parts.next().unwrap_or_default();
+        let params: Vec<f64> = parts.map(|p| p.parse::<f64>().map_err(|_| format!("invalid parameter '{}' in '{}'", p, spec)))
+            .collect::<Result<_, _>>()?;
+        let param = |i: usize, default: f64| params.get(i).copied().unwrap_or(default);
+        let strategy = match name{
+            "random" => StrategyConfig::Random,
+            "momentum" => StrategyConfig::Momentum{lookback:param(0, 2.0) as usize, threshold:param(1, 0.02)},
+            "mean_reversion" => StrategyConfig::MeanReversion{lookback:param(0, 3.0) as usize, threshold:param(1, 0.03)},
+            "buy_and_hold" => StrategyConfig::BuyAndHold,
+            "market_maker" => StrategyConfig::MarketMaker{spread:param(0, 0.02), qty:param(1, 10.0) as i64},
+            other => return Err(format!("unknown strategy '{}'", other)),
+        };
+        strategy.validate()?;
+        Ok(strategy)
+    }
+
+    pub fn validate(&self) -> Result<(), String>{
+        match *self{
+            StrategyConfig::Momentum{lookback, threshold} | StrategyConfig::MeanReversion{lookback, threshold} => {
+                if lookback < 1{return Err("lookback must be at least 1".to_string());}
+                if !threshold.is_finite() || threshold < 0.0{return Err("threshold can't be negative".to_string());}
+            }
+            StrategyConfig::MarketMaker{spread, qty} => {
+                if !(0.0..1.0).contains(&spread){return Err("spread must be between 0 and 1".to_string());}
+                if qty < 1{return Err("qty must be at least 1".to_string());}
+            }
+            StrategyConfig::Random | StrategyConfig::BuyAndHold => {}
+        }
+        Ok(())
+    }
+
+    pub fn build(&self) -> Box<dyn Strategy>{
+        match *self{
+            StrategyConfig::Random => Box::new(RandomStrategy),
+            StrategyConfig::Momentum{lookback, threshold} => Box::new(Momentum{history:PriceHistory::new(lookback + 1), lookback, threshold}),
+            StrategyConfig::MeanReversion{lookback, threshold} => Box::new(MeanReversion{history:PriceHistory::new(lookback), lookback, threshold}),
+            StrategyConfig::BuyAndHold => Box::new(BuyAndHold{bought:false}),
+            StrategyConfig::MarketMaker{spread, qty} => Box::new(MarketMaker{spread, qty:qty.into()}),
+        }
+    }
+}
+
+impl fmt::Display for StrategyConfig{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self{
+            StrategyConfig::Random => write!(f, "random"),
+            StrategyConfig::Momentum{lookback, threshold} => write!(f, "momentum (±{}% over {} stock lists)", threshold * 100.0, lookback),
+            StrategyConfig::MeanReversion{lookback, threshold} => write!(f, "mean reversion (±{}% from the {}-list mean)", threshold * 100.0, lookback),
+            StrategyConfig::BuyAndHold => write!(f, "buy and hold"),
+            StrategyConfig::MarketMaker{spread, qty} => write!(f, "market maker ({}% spread, {} shares)", spread * 100.0, qty),
+        }
+    }
+}
+
+// A strategy for one user, the others use `users.strategy`
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub struct UserStrategy{
+    pub user: i8,
+    #[serde(flatten)]
+    pub strategy: StrategyConfig,
+}
+
+// Last prices seen per stock, oldest first
+struct PriceHistory{
+    prices: HashMap<String, VecDeque<f64>>,
+    keep: usize,
+}
+
+impl PriceHistory{
+    fn new(keep: usize) -> PriceHistory{
+        PriceHistory{prices:HashMap::new(), keep}
+    }
+
+    fn push(&mut self, stocks: &[Stock]){
+        for stock in stocks{
+            let prices = self.prices.entry(stock.name.clone()).or_default();
+            prices.push_back(stock.value);
+            if prices.len() > self.keep{prices.pop_front();}
+        }
+    }
+
+    // Relative change over the whole window, once it's full
+    fn change(&self, name: &str) -> Option<f64>{
+        let prices = self.prices.get(name).filter(|p| p.len() == self.keep)?;
+        Some(prices.back()? / prices.front()? - 1.0)
+    }
+
+    // Relative distance of the last price from the window's mean, once it's full
+    fn deviation(&self, name: &str) -> Option<f64>{
+        let prices = self.prices.get(name).filter(|p| p.len() == self.keep)?;
+        let mean = prices.iter().sum::<f64>() / prices.len() as f64;
+        Some(prices.back()? / mean - 1.0)
+    }
+}
+
+// Take profit & cut loss around the price, mirrored for a short
+fn levels(price: f64, short: bool, behaviour: &UserBehaviour, rng: &mut StdRng) -> (f64, f64){
+    let up = rng.gen_range(behaviour.take_profit_pct.0..=behaviour.take_profit_pct.1);
+    let down = rng.gen_range(behaviour.cut_loss_pct.0..=behaviour.cut_loss_pct.1);
+    if short{(price * (1.0 - up), price * (1.0 + down))}else{(price * (1.0 + up), price * (1.0 - down))}
+}
+
+fn buy(stock: &Stock, ctx: &mut Context) -> Intent{
+    let (take_profit, cut_loss) = levels(stock.value, false, ctx.behaviour, ctx.rng);
+    let num_stock = ctx.rng.gen_range(ctx.behaviour.buy_qty.0..=ctx.behaviour.buy_qty.1).into();
+    let expire_after_ms = (ctx.behaviour.expire_after_ms > 0).then_some(ctx.behaviour.expire_after_ms as i64);
+    Intent{take_profit, cut_loss, trailing_stop:ctx.behaviour.trailing_stop, expire_after_ms, ..Intent::new(stock, Side::Buy, num_stock)}.typed(ctx.behaviour)
+}
+
+fn short(stock: &Stock, ctx: &mut Context) -> Intent{
+    let num_stock = ctx.rng.gen_range(ctx.behaviour.short_qty.0..=ctx.behaviour.short_qty.1).into();
+    let (take_profit, cut_loss) = levels(stock.value, true, ctx.behaviour, ctx.rng);
+    Intent{short:true, take_profit, cut_loss, ..Intent::new(stock, Side::Sell, num_stock)}.typed(ctx.behaviour)
+}
+
+// Sell held shares at the current price
+fn sell(stock: &Stock, broker: i8, num_stock: i128) -> Intent{
+    Intent{broker:Some(broker), ..Intent::new(stock, Side::Sell, num_stock)}
+}
+
+fn find<'a>(stocks: &'a [Stock], name: &str) -> Option<&'a Stock>{
+    stocks.iter().find(|s| s.name == name)
+}
+
+struct RandomStrategy;
+
+impl Strategy for RandomStrategy{
+    fn name(&self) -> String{
+        StrategyConfig::Random.to_string()
+    }
+
+    fn on_visit(&mut self, ctx: &mut Context) -> Vec<Intent>{
+        let behaviour = ctx.behaviour;
+        let mut intents = Vec::new();
+        for _ in 1..=ctx.rng.gen_range(behaviour.orders_per_visit.0..=behaviour.orders_per_visit.1){
+            let is_short = ctx.rng.gen_bool(behaviour.short_prob);
+            let stock = ctx.stocks[ctx.rng.gen_range(0..ctx.stocks.len())].clone();
+            intents.push(if is_short{short(&stock, ctx)}else{buy(&stock, ctx)});
+        }
+        intents
+    }
+
+    // Some users sell part of one holding
+    fn on_exit(&mut self, ctx: &mut Context) -> Vec<Intent>{
+        if ctx.holdings.is_empty() || !ctx.rng.gen_bool(ctx.behaviour.sell_prob){return Vec::new();}
+        let (stock_name, broker, available) = ctx.holdings[ctx.rng.gen_range(0..ctx.holdings.len())].clone();
+        let Some(stock) = find(ctx.stocks, &stock_name) else {return Vec::new()};
+        vec![sell(stock, broker, ctx.rng.gen_range(1..=available)).typed(ctx.behaviour)]
+    }
+}
+
+struct Momentum{
+    history: PriceHistory,
+    lookback: usize,
+    threshold: f64,
+}
+
+impl Strategy for Momentum{
+    fn name(&self) -> String{
+        StrategyConfig::Momentum{lookback:self.lookback, threshold:self.threshold}.to_string()
+    }
+
+    fn on_market_data(&mut self, stocks: &[Stock]){
+        self.history.push(stocks);
+    }
+
+    // Buy the strongest risers, short the steepest fallers when shorting is on
+    fn on_visit(&mut self, ctx: &mut Context) -> Vec<Intent>{
+        let mut moves: Vec<(f64, Stock)> = ctx.stocks.iter()
+            .filter_map(|s| self.history.change(&s.name).map(|change| (change, s.clone()))).collect();
+        moves.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let max = ctx.behaviour.orders_per_visit.1 as usize;
+        let mut intents = Vec::new();
+        for (_, stock) in moves.iter().filter(|(change, _)| *change > self.threshold).take(max){
+            intents.push(buy(stock, ctx));
+        }
+        if ctx.behaviour.short_prob > 0.0{
+            for (_, stock) in moves.iter().rev().filter(|(change, _)| *change < -self.threshold).take(max){
+                intents.push(short(stock, ctx));
+            }
+        }
+        intents
+    }
+
+    // Get out of whatever turned down, and raise the cut loss under what's still rising
+    fn on_exit(&mut self, ctx: &mut Context) -> Vec<Intent>{
+        for (name, broker, _) in ctx.holdings.clone(){
+            let Some(stock) = find(ctx.stocks, &name) else {continue};
+            if self.history.change(&name).is_some_and(|change| change > self.threshold){
+                ctx.amend_levels(&name, broker, None, Some(stock.value * (1.0 - ctx.behaviour.cut_loss_pct.0)));
+            }
+        }
+        ctx.holdings.iter().filter(|(name, _, _)| self.history.change(name).is_some_and(|change| change < 0.0))
+            .filter_map(|(name, broker, qty)| find(ctx.stocks, name).map(|stock| sell(stock, *broker, *qty).typed(ctx.behaviour))).collect()
+    }
+}
+
+struct MeanReversion{
+    history: PriceHistory,
+    lookback: usize,
+    threshold: f64,
+}
+
+impl Strategy for MeanReversion{
+    fn name(&self) -> String{
+        StrategyConfig::MeanReversion{lookback:self.lookback, threshold:self.threshold}.to_string()
+    }
+
+    fn on_market_data(&mut self, stocks: &[Stock]){
+        self.history.push(stocks);
+    }
+
+    // Buy what's furthest below its mean, short what's furthest above when shorting is on
+    fn on_visit(&mut self, ctx: &mut Context) -> Vec<Intent>{
+        let mut deviations: Vec<(f64, Stock)> = ctx.stocks.iter()
+            .filter_map(|s| self.history.deviation(&s.name).map(|deviation| (deviation, s.clone()))).collect();
+        deviations.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let max = ctx.behaviour.orders_per_visit.1 as usize;
+        let mut intents = Vec::new();
+        for (_, stock) in deviations.iter().filter(|(deviation, _)| *deviation < -self.threshold).take(max){
+            intents.push(buy(stock, ctx));
+        }
+        if ctx.behaviour.short_prob > 0.0{
+            for (_, stock) in deviations.iter().rev().filter(|(deviation, _)| *deviation > self.threshold).take(max){
+                intents.push(short(stock, ctx));
+            }
+        }
+        intents
+    }
+
+    // Sell what's back at or above its mean
+    fn on_exit(&mut self, ctx: &mut Context) -> Vec<Intent>{
+        ctx.holdings.iter().filter(|(name, _, _)| self.history.deviation(name).is_some_and(|deviation| deviation >= 0.0))
+            .filter_map(|(name, broker, qty)| find(ctx.stocks, name).map(|stock| sell(stock, *broker, *qty).typed(ctx.behaviour))).collect()
+    }
+}
+
+struct BuyAndHold{
+    bought: bool,
+}
+
+impl Strategy for BuyAndHold{
+    fn name(&self) -> String{
+        StrategyConfig::BuyAndHold.to_string()
+    }
+
+    // A basket of different stocks, held whatever the price does
+    fn on_visit(&mut self, ctx: &mut Context) -> Vec<Intent>{
+        if self.bought{return Vec::new();}
+        self.bought = true;
+        let count = ctx.behaviour.orders_per_visit.1 as usize;
+        let basket: Vec<Stock> = ctx.stocks.choose_multiple(ctx.rng, count).cloned().collect();
+        basket.iter().map(|stock| {
+            let num_stock = ctx.rng.gen_range(ctx.behaviour.buy_qty.0..=ctx.behaviour.buy_qty.1).into();
+            // levels the broker's monitor never reaches
+            Intent{take_profit:f64::MAX, cut_loss:0.0, ..Intent::new(stock, Side::Buy, num_stock)}.typed(ctx.behaviour)
+        }).collect()
+    }
+}
+
+struct MarketMaker{
+    spread: f64,
+    qty: i128,
+}
+
+impl Strategy for MarketMaker{
+    fn name(&self) -> String{
+        StrategyConfig::MarketMaker{spread:self.spread, qty:self.qty as i64}.to_string()
+    }
+
+    // Bid below the price on a few stocks and offer what it holds above it.
+    // A filled bid is taken profit on once the price reaches the offer side.
+    fn on_visit(&mut self, ctx: &mut Context) -> Vec<Intent>{
+        let count = ctx.behaviour.orders_per_visit.1 as usize;
+        let mut intents: Vec<Intent> = ctx.stocks.choose_multiple(ctx.rng, count).map(|stock| self.bid(stock, self.qty)).collect();
+        intents.extend(self.offers(ctx));
+        intents
+    }
+
+    // Re-quote the bids still resting at the current price, and pull those on stocks
+    // that moved more than the spread
+    fn on_exit(&mut self, ctx: &mut Context) -> Vec<Intent>{
+        let half = self.spread / 2.0;
+        for open in ctx.orders.clone().into_iter().filter(|o| o.side == Side::Buy){
+            let Some(stock) = find(ctx.stocks, &open.stock_name) else {continue};
+            let moved = stock.value / (open.price / (1.0 - half)) - 1.0;
+            if moved.abs() > self.spread{
+                ctx.cancel(&open.order_id);
+            }else if to_ticks(stock.value * (1.0 - half)) != to_ticks(open.price){
+                ctx.replace(&open.order_id, self.bid(stock, open.leaves_qty));
+            }
+        }
+        self.offers(ctx)
+    }
+}
+
+impl MarketMaker{
+    fn bid(&self, stock: &Stock, num_stock: i128) -> Intent{
+        let half = self.spread / 2.0;
+        let bid = stock.value * (1.0 - half);
+        Intent{price:bid, take_profit:stock.value * (1.0 + half), cut_loss:bid * (1.0 - self.spread * 2.0), ..Intent::new(stock, Side::Buy, num_stock)}
+    }
+
+    fn offers(&self, ctx: &Context) -> Vec<Intent>{
+        ctx.holdings.iter().filter_map(|(name, broker, qty)| find(ctx.stocks, name).map(|stock| {
+            let ask = stock.value * (1.0 + self.spread / 2.0);
+            Intent{price:ask, take_profit:ask, cut_loss:ask, ..sell(stock, *broker, *qty)}
+        })).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::rng::{component_rng, USERS_STREAM};
+
+    fn stocks(prices: &[(&str, f64)]) -> Vec<Stock>{
+        prices.iter().map(|(name, value)| Stock{name:name.to_string(), value:*value}).collect()
+    }
+
+    // One visit, or the user leaving, with the last stock list on the page
+    fn decide(strategy: &mut dyn Strategy, page: &[Stock], holdings: Vec<(String, i8, i128)>, orders: Vec<OpenOrder>, behaviour: &UserBehaviour, visit: bool) -> (Vec<Intent>, Vec<Request>){
+        let mut rng = component_rng(7, USERS_STREAM);
+        let mut ctx = Context{user:1, stocks:page, holdings, orders, behaviour, rng:&mut rng, requests:Vec::new()};
+        let intents = if visit{strategy.on_visit(&mut ctx)}else{strategy.on_exit(&mut ctx)};
+        (intents, ctx.requests)
+    }
+
+    #[test]
+    fn momentum_buys_risers_and_sells_fallers(){
+        let behaviour = UserBehaviour::default();
+        let mut strategy = StrategyConfig::Momentum{lookback:2, threshold:0.02}.build();
+        for prices in [[("a", 100.0), ("b", 100.0)], [("a", 103.0), ("b", 99.0)], [("a", 106.0), ("b", 97.0)]]{
+            strategy.on_market_data(&stocks(&prices));
+        }
+        let page = stocks(&[("a", 106.0), ("b", 97.0)]);
+        let (intents, _) = decide(&mut *strategy, &page, Vec::new(), Vec::new(), &behaviour, true);
+        assert_eq!(intents.len(), 1);
+        assert_eq!((intents[0].stock_name.as_str(), intents[0].side, intents[0].price), ("a", Side::Buy, 106.0));
+
+        let holdings = vec![("a".to_string(), 1, 5), ("b".to_string(), 2, 4)];
+        let (intents, requests) = decide(&mut *strategy, &page, holdings, Vec::new(), &behaviour, false);
+        assert_eq!(intents.len(), 1);
+        assert_eq!((intents[0].stock_name.as_str(), intents[0].side, intents[0].num_stock, intents[0].broker), ("b", Side::Sell, 4, Some(2)));
+        // the riser's cut loss follows it up
+        assert!(matches!(&requests[..], [Request::AmendLevels{stock_name, broker:1, take_profit:None, cut_loss:Some(cut_loss)}]
+            if stock_name == "a" && (*cut_loss - 106.0 * 0.98).abs() < 1e-9));
+    }
+
+    #[test]
+    fn mean_reversion_buys_below_and_shorts_above_the_mean(){
+        let behaviour = UserBehaviour{short_prob:1.0, ..UserBehaviour::default()};
+        let mut strategy = StrategyConfig::MeanReversion{lookback:3, threshold:0.03}.build();
+        for prices in [[("a", 100.0), ("b", 100.0)], [("a", 100.0), ("b", 100.0)], [("a", 90.0), ("b", 115.0)]]{
+            strategy.on_market_data(&stocks(&prices));
+        }
+        let page = stocks(&[("a", 90.0), ("b", 115.0)]);
+        let (intents, _) = decide(&mut *strategy, &page, Vec::new(), Vec::new(), &behaviour, true);
+        let sides: Vec<(&str, Side, bool)> = intents.iter().map(|i| (i.stock_name.as_str(), i.side, i.short)).collect();
+        assert_eq!(sides, vec![("a", Side::Buy, false), ("b", Side::Sell, true)]);
+
+        // only what's back above its mean is sold
+        let holdings = vec![("a".to_string(), 1, 5), ("b".to_string(), 1, 3)];
+        let (intents, _) = decide(&mut *strategy, &page, holdings, Vec::new(), &behaviour, false);
+        assert_eq!(intents.len(), 1);
+        assert_eq!((intents[0].stock_name.as_str(), intents[0].side, intents[0].num_stock, intents[0].short), ("b", Side::Sell, 3, false));
+    }
+
+    #[test]
+    fn buy_and_hold_buys_one_basket(){
+        let behaviour = UserBehaviour{orders_per_visit:(1, 2), ..UserBehaviour::default()};
+        let mut strategy = StrategyConfig::BuyAndHold.build();
+        let page = stocks(&[("a", 10.0), ("b", 20.0), ("c", 30.0)]);
+        let (intents, _) = decide(&mut *strategy, &page, Vec::new(), Vec::new(), &behaviour, true);
+        assert_eq!(intents.len(), 2);
+        assert_ne!(intents[0].stock_name, intents[1].stock_name);
+        assert!(intents.iter().all(|i| i.side == Side::Buy && i.cut_loss == 0.0 && i.take_profit == f64::MAX));
+        assert!(decide(&mut *strategy, &page, Vec::new(), Vec::new(), &behaviour, true).0.is_empty());
+        assert!(decide(&mut *strategy, &page, vec![("a".to_string(), 1, 5)], Vec::new(), &behaviour, false).0.is_empty());
+    }
+
+    #[test]
+    fn market_maker_quotes_around_the_price(){
+        let behaviour = UserBehaviour{orders_per_visit:(1, 1), ..UserBehaviour::default()};
+        let mut strategy = StrategyConfig::MarketMaker{spread:0.02, qty:5}.build();
+        let page = stocks(&[("a", 100.0)]);
+        let (intents, _) = decide(&mut *strategy, &page, vec![("a".to_string(), 3, 2)], Vec::new(), &behaviour, true);
+        assert_eq!(intents.len(), 2);
+        assert_eq!((intents[0].side, intents[0].num_stock), (Side::Buy, 5));
+        assert!((intents[0].price - 99.0).abs() < 1e-9 && (intents[0].take_profit - 101.0).abs() < 1e-9);
+        assert_eq!((intents[1].side, intents[1].num_stock, intents[1].broker), (Side::Sell, 2, Some(3)));
+        assert!((intents[1].price - 101.0).abs() < 1e-9);
+
+        // a bid at 99 is left alone at 100, re-quoted at 101 and pulled at 105
+        let bid = OpenOrder{order_id:"U1-1".to_string(), stock_name:"a".to_string(), side:Side::Buy, price:99.0, leaves_qty:4, broker:1};
+        let requests = |price: f64, strategy: &mut dyn Strategy| decide(strategy, &stocks(&[("a", price)]), Vec::new(), vec![bid.clone()], &behaviour, false).1;
+        assert!(requests(100.0, &mut *strategy).is_empty());
+        assert!(matches!(&requests(101.0, &mut *strategy)[..], [Request::Replace{order_id, intent}]
+            if order_id == "U1-1" && intent.num_stock == 4 && (intent.price - 99.99).abs() < 1e-9));
+        assert!(matches!(&requests(105.0, &mut *strategy)[..], [Request::Cancel{order_id}] if order_id == "U1-1"));
+    }
+}
@@ -0,0 +1,77 @@
+use std::time::Duration;
+use amiquip::{Channel, Connection, Consumer, ConsumerMessage, ConsumerOptions, Exchange, ExchangeDeclareOptions, ExchangeType, FieldTable, Publish, QueueDeclareOptions};
+use super::{Result, Subscription, Transport, TransportError};
+
+// RabbitMQ-backed transport, one connection and one channel per handle.
+// Field order matters: the channel has to be dropped before its connection.
+pub struct AmqpTransport{
+    channel: Channel,
+    connection: Connection,
+}
+
+impl AmqpTransport{
+    pub fn open(url: &str) -> Result<AmqpTransport>{
+        let mut connection = Connection::insecure_open(url)?;
+        // None says let the library choose the channel ID.
+        let channel = connection.open_channel(None)?;
+        Ok(AmqpTransport{channel, connection})
+    }
+}
+
+impl Transport for AmqpTransport{
+    fn declare_queue(&self, queue: &str) -> Result<()>{
+        self.channel.queue_declare(queue, QueueDeclareOptions::default())?;
+        Ok(())
+    }
+
+    fn publish(&self, routing_key: &str, body: &[u8]) -> Result<()>{
+        Exchange::direct(&self.channel).publish(Publish::new(body, routing_key))?;
+        Ok(())
+    }
+
+    fn consume(&self, queue: &str) -> Result<Box<dyn Subscription + '_>>{
+        let queue = self.channel.queue_declare(queue, QueueDeclareOptions::default())?;
+        let consumer = queue.consume(ConsumerOptions::default())?;
+        Ok(Box::new(AmqpSubscription{consumer}))
+    }
+
+    fn broadcast(&self, exchange: &str, body: &[u8]) -> Result<()>{
+        let exchange = self.channel.exchange_declare(ExchangeType::Fanout, exchange, ExchangeDeclareOptions::default())?;
+        exchange.publish(Publish::new(body, ""))?;
+        Ok(())
+    }
+
+    fn subscribe(&self, exchange: &str) -> Result<Box<dyn Subscription + '_>>{
+        let exchange = self.channel.exchange_declare(ExchangeType::Fanout, exchange, ExchangeDeclareOptions::default())?;
+        // server-named queue, deleted with this connection
+        let queue = self.channel.queue_declare("", QueueDeclareOptions{exclusive:true, ..QueueDeclareOptions::default()})?;
+        queue.bind(&exchange, "", FieldTable::new())?;
+        let consumer = queue.consume(ConsumerOptions::default())?;
+        Ok(Box::new(AmqpSubscription{consumer}))
+    }
+
+    fn close(self: Box<Self>) -> Result<()>{
+        let AmqpTransport{channel, connection} = *self;
+        channel.close()?;
+        connection.close()?;
+        Ok(())
+    }
+}
+
+struct AmqpSubscription<'a>{
+    consumer: Consumer<'a>,
+}
+
+impl Subscription for AmqpSubscription<'_>{
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>>{
+        match self.consumer.receiver().recv_timeout(timeout){
+            Ok(ConsumerMessage::Delivery(delivery)) => {
+                let body = delivery.body.clone();
+                self.consumer.ack(delivery)?;
+                Ok(Some(body))
+            }
+            Ok(other) => Err(TransportError::Closed(format!("{:?}", other))),
+            Err(_) => Ok(None),
+        }
+    }
+}
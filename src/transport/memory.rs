@@ -0,0 +1,76 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use super::{Result, Subscription, Transport, TransportError};
+
+type Queue = (Sender<Vec<u8>>, Receiver<Vec<u8>>);
+// subscribers' senders, by fanout exchange
+type Fanouts = HashMap<String, Vec<Sender<Vec<u8>>>>;
+
+// In-process transport built on crossbeam channels, so the exchange, the users
+// and the brokers can all run inside one process for tests and demos.
+// Cloning shares the same set of queues. Consumers on the same queue compete
+// for messages like they do on RabbitMQ, but unlike RabbitMQ a message
+// published before anyone declared the queue is buffered instead of dropped.
+// Broadcasts only reach the subscribers there are at the time, as on RabbitMQ.
+#[derive(Clone, Default)]
+pub struct MemoryTransport{
+    queues: Arc<Mutex<HashMap<String, Queue>>>,
+    fanouts: Arc<Mutex<Fanouts>>,
+}
+
+impl MemoryTransport{
+    pub fn new() -> MemoryTransport{
+        MemoryTransport::default()
+    }
+
+    fn queue(&self, name: &str) -> Queue{
+        let mut queues = self.queues.lock().unwrap();
+        queues.entry(name.to_string()).or_insert_with(unbounded).clone()
+    }
+}
+
+impl Transport for MemoryTransport{
+    fn declare_queue(&self, queue: &str) -> Result<()>{
+        self.queue(queue);
+        Ok(())
+    }
+
+    fn publish(&self, routing_key: &str, body: &[u8]) -> Result<()>{
+        let (sender, _) = self.queue(routing_key);
+        sender.send(body.to_vec()).map_err(|err| TransportError::Closed(err.to_string()))
+    }
+
+    fn consume(&self, queue: &str) -> Result<Box<dyn Subscription + '_>>{
+        let (_, receiver) = self.queue(queue);
+        Ok(Box::new(MemorySubscription{receiver}))
+    }
+
+    fn broadcast(&self, exchange: &str, body: &[u8]) -> Result<()>{
+        let mut fanouts = self.fanouts.lock().unwrap();
+        // a failed send means the subscription was dropped
+        if let Some(subscribers) = fanouts.get_mut(exchange){
+            subscribers.retain(|sender| sender.send(body.to_vec()).is_ok());
+        }
+        Ok(())
+    }
+
+    fn subscribe(&self, exchange: &str) -> Result<Box<dyn Subscription + '_>>{
+        let (sender, receiver) = unbounded();
+        self.fanouts.lock().unwrap().entry(exchange.to_string()).or_default().push(sender);
+        Ok(Box::new(MemorySubscription{receiver}))
+    }
+}
+
+struct MemorySubscription{
+    receiver: Receiver<Vec<u8>>,
+}
+
+impl Subscription for MemorySubscription{
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>>{
+        match self.receiver.recv_timeout(timeout){
+            Ok(body) => Ok(Some(body)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(TransportError::Closed("queue disconnected".to_string())),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::{process, sync::atomic::{AtomicU64, Ordering}, time::Duration};
+use crate::latency::{self, now_micros};
+use crate::logging::{self, Component};
+use crate::metrics;
+use super::{Result, Subscription, Transport};
+
+// Every message goes out as "STAMP <correlation id> <send time µs>\n<body>".
+// The receiving side strips the header and records the hop's latency.
+const STAMP: &[u8] = b"STAMP ";
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Header of a stamped message
+#[derive(Clone,Debug,PartialEq)]
+pub struct Stamp{
+    // the order id for anything about an order (the order itself, the exchange's order,
+    // its executions, notices and reports), so one order's hops share it.
+    // "<process id>-<n>" otherwise, unique across the binaries
+    pub correlation_id: String,
+    pub sent_at: i64, // µs since epoch
+}
+
+// The order a message is about: its own `order_id`, or the one of the variant it
+// carries (`{"Cancelled":{"order_id":..}}`). Amend requests go by their request id
+fn order_id_of(body: &[u8]) -> Option<String>{
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    let object = value.as_object()?;
+    let fields = match object.len(){
+        1 => object.values().next().and_then(|inner| inner.as_object()).unwrap_or(object),
+        _ => object,
+    };
+    let id = fields.get("order_id").or_else(|| fields.get("request_id"))?.as_str()?;
+    // the header is space separated
+    (!id.is_empty() && !id.contains(char::is_whitespace)).then(|| id.to_string())
+}
+
+pub fn correlation_id(body: &[u8]) -> String{
+    order_id_of(body).unwrap_or_else(|| format!("{}-{}", process::id(), NEXT_ID.fetch_add(1, Ordering::Relaxed)))
+}
+
+pub fn stamp(body: &[u8]) -> Vec<u8>{
+    let mut stamped = format!("STAMP {} {}\n", correlation_id(body), now_micros()).into_bytes();
+    stamped.extend_from_slice(body);
+    stamped
+}
+
+// Split a message into its stamp and body, unstamped messages pass through as they are
+pub fn unstamp(message: Vec<u8>) -> (Option<Stamp>, Vec<u8>){
+    if !message.starts_with(STAMP){return (None, message);}
+    let Some(end) = message.iter().position(|b| *b == b'\n') else {return (None, message)};
+    let header = String::from_utf8_lossy(&message[STAMP.len()..end]).to_string();
+    let mut fields = header.split(' ');
+    match (fields.next(), fields.next().and_then(|t| t.parse::<i64>().ok())){
+        (Some(id), Some(sent_at)) => (Some(Stamp{correlation_id:id.to_string(), sent_at}), message[end+1..].to_vec()),
+        _ => (None, message),
+    }
+}
+
+// Wraps any transport so every message is stamped and timed
+pub struct StampedTransport{
+    inner: Box<dyn Transport>,
+}
+
+impl StampedTransport{
+    pub fn new(inner: Box<dyn Transport>) -> StampedTransport{
+        StampedTransport{inner}
+    }
+}
+
+impl Transport for StampedTransport{
+    fn declare_queue(&self, queue: &str) -> Result<()>{
+        self.inner.declare_queue(queue)
+    }
+
+    fn publish(&self, routing_key: &str, body: &[u8]) -> Result<()>{
+        latency::record_sent(routing_key, body.len());
+        self.inner.publish(routing_key, &stamp(body))
+    }
+
+    fn consume(&self, queue: &str) -> Result<Box<dyn Subscription + '_>>{
+        Ok(Box::new(StampedSubscription{inner:self.inner.consume(queue)?, hop:queue.to_string()}))
+    }
+
+    fn broadcast(&self, exchange: &str, body: &[u8]) -> Result<()>{
+        latency::record_sent(exchange, body.len());
+        self.inner.broadcast(exchange, &stamp(body))
+    }
+
+    fn subscribe(&self, exchange: &str) -> Result<Box<dyn Subscription + '_>>{
+        Ok(Box::new(StampedSubscription{inner:self.inner.subscribe(exchange)?, hop:exchange.to_string()}))
+    }
+
+    fn close(self: Box<Self>) -> Result<()>{
+        self.inner.close()
+    }
+}
+
+struct StampedSubscription<'a>{
+    inner: Box<dyn Subscription + 'a>,
+    hop: String,
+}
+
+impl Subscription for StampedSubscription<'_>{
+    fn recv_timeout(&self, timeout: Duration) -> Result<Option<Vec<u8>>>{
+        let Some(message) = self.inner.recv_timeout(timeout)? else {
+            // zero timeouts are polls, not waits that came up empty
+            if !timeout.is_zero(){metrics::inc(&metrics::RECEIVE_TIMEOUTS, &[("queue", &self.hop)]);}
+            return Ok(None);
+        };
+        let received_at = now_micros();
+        let (stamp, body) = unstamp(message);
+        let latency = stamp.as_ref().map(|s| ((received_at - s.sent_at).max(0) as u64, s.correlation_id.as_str()));
+        // one line per message, so only with a `latency` filter
+        if let Some((micros, id)) = latency.filter(|_| logging::filtered(Component::Named("Latency"))){
+            logging::debug(Component::Named("Latency"), format!("{} took {}µs through {}", id, micros, self.hop))
+                .field("correlation_id", id).field("hop", &self.hop).field("latency_us", micros).emit();
+        }
+        latency::record_received(&self.hop, body.len(), latency);
+        Ok(Some(body))
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn orders_are_correlated_by_their_id(){
+        let (header, body) = unstamp(stamp(br#"{"order_id":"U3-17","broker_no":1}"#));
+        assert_eq!(header.unwrap().correlation_id, "U3-17");
+        assert_eq!(body, br#"{"order_id":"U3-17","broker_no":1}"#);
+        let (header, _) = unstamp(stamp(br#"{"Cancelled":{"order_id":"U3-17","broker_no":1}}"#));
+        assert_eq!(header.unwrap().correlation_id, "U3-17");
+        let (header, _) = unstamp(stamp(br#"{"AmendLevels":{"request_id":"U3-18","client_id":3}}"#));
+        assert_eq!(header.unwrap().correlation_id, "U3-18");
+    }
+
+    #[test]
+    fn other_messages_get_a_process_unique_id(){
+        let first = correlation_id(br#"{"Heartbeat":{"seq":4,"timestamp":0}}"#);
+        let second = correlation_id(b"not json");
+        assert!(first.starts_with(&format!("{}-", process::id())));
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn unstamped_messages_pass_through(){
+        assert_eq!(unstamp(b"plain".to_vec()), (None, b"plain".to_vec()));
+    }
+}
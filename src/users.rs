@@ -0,0 +1,309 @@
+use std::{collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, thread, time::Duration};
+use crossbeam_channel::Receiver;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use crate::config::Config;
+use crate::logging::{self, Component};
+use crate::message::{decode, BrokerStatus, ExecutionReport, OrderControl, OrderStatus, OrderType, Side, TimeInForce};
+use crate::order::now_millis;
+use crate::purchase::TrailingStop;
+use crate::router::OrderRouter;
+use crate::session::{self, Session};
+use crate::strategy::{Context, Intent, OpenOrder, Request, Strategy, StrategyConfig, UserStrategy};
+use crate::transport::{Result, Subscription, Transport};
+use crate::{Order, Stock, ANSI_BOLD_GREEN, ANSI_BOLD_RED, ANSI_RESET};
+
+// How users trade, ranges are [min, max] and drawn uniformly
+#[derive(Clone,Debug,Serialize,Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserBehaviour{
+    pub sell_prob: f64, // chance a user sells some holdings before leaving
+    pub short_prob: f64, // chance each order is a short sell instead of a buy, 0 disables shorting
+    pub orders_per_visit: (u32, u32),
+    pub buy_qty: (i64, i64),
+    pub short_qty: (i64, i64),
+    pub take_profit_pct: (f64, f64), // above the price paid
+    pub cut_loss_pct: (f64, f64), // below the price paid
+    pub trailing_stop: Option<TrailingStop>, // on every holding bought, "5%" or an amount
+    pub expire_after_ms: u64, // holdings are sold this long after the fill, 0 keeps them
+    pub order_type: OrderType, // strategies' own quotes are always limit orders
+    pub time_in_force: TimeInForce,
+    pub stop_offset_pct: f64, // stop orders trigger this far beyond the price on the page
+    pub strategy: StrategyConfig, // for users missing from strategies
+    pub strategies: Vec<UserStrategy>,
+}
+
+impl Default for UserBehaviour{
+    fn default() -> Self {
+        UserBehaviour{sell_prob:0.5, short_prob:0.0, orders_per_visit:(1, 10), buy_qty:(1, 30), short_qty:(1, 10),
+            take_profit_pct:(0.05, 0.1), cut_loss_pct:(0.02, 0.08), trailing_stop:None, expire_after_ms:0,
+            order_type:OrderType::Limit, time_in_force:TimeInForce::Gtc, stop_offset_pct:0.02, strategy:StrategyConfig::default(), strategies:Vec::new()}
+    }
+}
+
+impl UserBehaviour{
+    pub fn strategy_for(&self, user: i8) -> &StrategyConfig{
+        self.strategies.iter().find(|s| s.user == user).map(|s| &s.strategy).unwrap_or(&self.strategy)
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), String>{
+        for (name, prob) in [("sell_prob", self.sell_prob), ("short_prob", self.short_prob)]{
+            if !(0.0..=1.0).contains(&prob){return Err(format!("{} must be between 0 and 1", name));}
+        }
+        let (min, max) = self.orders_per_visit;
+        if min < 1 || min > max{return Err("orders_per_visit must be [min, max] with 1 <= min <= max".to_string());}
+        for (name, (min, max)) in [("buy_qty", self.buy_qty), ("short_qty", self.short_qty)]{
+            if min < 1 || min > max{return Err(format!("{} must be [min, max] with 1 <= min <= max", name));}
+        }
+        for (name, (min, max)) in [("take_profit_pct", self.take_profit_pct), ("cut_loss_pct", self.cut_loss_pct)]{
+            if !(0.0..1.0).contains(&min) || !(min..1.0).contains(&max){
+                return Err(format!("{} must be [min, max] with 0 <= min <= max < 1", name));
+            }
+        }
+        if !(0.0..1.0).contains(&self.stop_offset_pct){return Err("stop_offset_pct must be between 0 and 1".to_string());}
+        self.strategy.validate().map_err(|err| format!("strategy: {}", err))?;
+        for (i, s) in self.strategies.iter().enumerate(){
+            if s.user < 1{return Err("strategies: user must be at least 1".to_string());}
+            if self.strategies[..i].iter().any(|other| other.user == s.user){return Err(format!("strategies: user {} is listed twice", s.user));}
+            s.strategy.validate().map_err(|err| format!("strategies for user {}: {}", s.user, err))?;
+        }
+        Ok(())
+    }
+}
+
+// What each user holds at each broker according to their execution reports,
+// so they only sell shares they own, through the broker holding them.
+// Shares in a working sell order aren't available.
+#[derive(Default)]
+pub struct Holdings{
+    positions: HashMap<(i8, String, i8), i128>, // (user, stock, broker) -> shares
+    open_sells: HashMap<String, (i8, String, i8, i128)>, // order id -> (user, stock, broker, unfilled qty)
+    orders: HashMap<String, (i8, OpenOrder)>, // order id -> (user, order) for every order still working
+}
+
+impl Holdings{
+    pub fn available(&self, client_id: i8, stock_name: &str, broker_no: i8) -> i128{
+        let held = self.positions.get(&(client_id, stock_name.to_string(), broker_no)).copied().unwrap_or(0);
+        let selling: i128 = self.open_sells.values().filter(|(id, name, broker, _)| *id == client_id && name == stock_name && *broker == broker_no)
+            .map(|(_, _, _, qty)| qty).sum();
+        held - selling
+    }
+
+    // Stocks the user can still sell, with the broker holding them and the quantity
+    pub fn sellable(&self, client_id: i8) -> Vec<(String, i8, i128)>{
+        let mut stocks: Vec<(String, i8, i128)> = self.positions.keys().filter(|(id, _, _)| *id == client_id)
+            .map(|(_, name, broker)| (name.clone(), *broker, self.available(client_id, name, *broker))).filter(|(_, _, qty)| *qty > 0).collect();
+        stocks.sort();
+        stocks
+    }
+
+    // The user's working orders, oldest first
+    pub fn open_orders(&self, client_id: i8) -> Vec<OpenOrder>{
+        let mut orders: Vec<(u64, OpenOrder)> = self.orders.values().filter(|(id, _)| *id == client_id)
+            .map(|(_, open)| (open.order_id.rsplit('-').next().and_then(|seq| seq.parse().ok()).unwrap_or(0), open.clone())).collect();
+        orders.sort_by_key(|(seq, _)| *seq);
+        orders.into_iter().map(|(_, open)| open).collect()
+    }
+
+    fn submit(&mut self, order: &Order, broker_no: i8){
+        if order.side == Side::Sell && !order.short{
+            self.open_sells.insert(order.order_id.clone(), (order.client_id, order.stock_name.clone(), broker_no, order.num_stock));
+        }
+        self.orders.insert(order.order_id.clone(), (order.client_id, OpenOrder{order_id:order.order_id.clone(), stock_name:order.stock_name.clone(),
+            side:order.side, price:order.bid_price, leaves_qty:order.num_stock, broker:broker_no}));
+    }
+
+    fn apply(&mut self, report: &ExecutionReport){
+        let qty = match report.side{Side::Buy => report.last_qty, Side::Sell => -report.last_qty};
+        *self.positions.entry((report.client_id, report.stock_name.clone(), report.broker_no)).or_default()+=qty;
+        // replies to cancels and amends leave the order as it was
+        if matches!(report.status, OrderStatus::CancelRejected{..} | OrderStatus::LevelsAmended | OrderStatus::AmendRejected{..}){return;}
+        if let Some(open) = self.open_sells.get_mut(&report.order_id){
+            open.3 = report.leaves_qty;
+            if open.3 == 0{self.open_sells.remove(&report.order_id);}
+        }
+        if let Some((_, open)) = self.orders.get_mut(&report.order_id){
+            open.leaves_qty = report.leaves_qty;
+            if open.leaves_qty == 0{self.orders.remove(&report.order_id);}
+        }
+    }
+}
+
+static NEXT_ORDER_SEQ: AtomicU64 = AtomicU64::new(1);
+
+// Order IDs are unique for the whole run, e.g. "U3-17"
+pub fn next_order_id(client_id: i8) -> String{
+    format!("U{}-{}", client_id, NEXT_ORDER_SEQ.fetch_add(1, Ordering::Relaxed))
+}
+
+// Number an intent and address its reports to the users
+fn to_order(id:i8,intent: &Intent,config: &Config)-> Order{
+    Order{order_id:next_order_id(id),client_id:id,stock_name:intent.stock_name.clone(),side:intent.side,bid_price:intent.price,take_profit:intent.take_profit,
+        cut_loss:intent.cut_loss,num_stock:intent.num_stock,timestamp:now_millis(),reply_to:config.queues.user_reports.clone(),short:intent.short,
+        trailing_stop:intent.trailing_stop,expire_after_ms:intent.expire_after_ms,order_type:intent.order_type,time_in_force:intent.time_in_force,stop_price:intent.stop_price}
+}
+
+fn send_order(transport: &dyn Transport, config: &Config, holdings: &mut Holdings, order: &Order, broker_no: i8) -> Result<()>{
+    holdings.submit(order, broker_no);
+    let user_list_json =serde_json::to_string(order).expect("Failed to serialized");
+    transport.publish(&config.queues.orders_for(broker_no), user_list_json.as_bytes())
+}
+
+// Send a strategy's cancels, replaces and amends to the brokers handling the orders & holdings
+fn send_requests(transport: &dyn Transport, config: &Config, router: &mut OrderRouter, holdings: &mut Holdings, id: i8, requests: Vec<Request>) -> Result<()>{
+    let reply_to = config.queues.user_reports.clone();
+    for request in requests{
+        let (broker_no, control, what) = match request{
+            Request::Cancel{order_id} => {
+                let Some((_, open)) = holdings.orders.get(&order_id).cloned() else {continue};
+                let what = format!("cancel of order {}", order_id);
+                (open.broker, OrderControl::Cancel{order_id, client_id:id, stock_name:open.stock_name, side:open.side, reply_to:reply_to.clone()}, what)
+            }
+            Request::Replace{order_id, intent} => {
+                let Some(broker_no) = router.handled_by(&order_id) else {continue};
+                let order = to_order(id, &intent, config);
+                router.assign(&order.order_id, broker_no);
+                holdings.submit(&order, broker_no);
+                let what = format!("replace of order {} by {}", order_id, order.order_id);
+                (broker_no, OrderControl::Replace{order_id, order}, what)
+            }
+            Request::AmendLevels{stock_name, broker, take_profit, cut_loss} => {
+                let request_id = next_order_id(id);
+                let what = format!("amend {} of [{}] levels", request_id, stock_name);
+                (broker, OrderControl::AmendLevels{request_id, client_id:id, stock_name, take_profit, cut_loss, reply_to:reply_to.clone()}, what)
+            }
+        };
+        logging::info(Component::User(id), format!("{} had send to broker {}..", what, broker_no)).user(id).field("broker", broker_no).emit();
+        let control_json = serde_json::to_string(&control).expect("Failed to serialized");
+        transport.publish(&config.queues.orders_for(broker_no), control_json.as_bytes())?;
+    }
+    Ok(())
+}
+
+// Keep the router up to date with the brokers' broadcast statuses
+fn read_statuses(statuses: &dyn Subscription, router: &mut OrderRouter, timeout: Duration) -> Result<()>{
+    while let Some(body) = statuses.recv_timeout(timeout)?{
+        let Some(status) = decode::<BrokerStatus>(Component::Users, &body) else {continue};
+        router.update(status);
+    }
+    Ok(())
+}
+
+// Print every execution report already waiting on the reply queue, and pass it to the user's strategy
+fn read_reports(reports: &dyn Subscription, holdings: &mut Holdings, strategies: &mut Strategies, timeout: Duration) -> Result<()>{
+    while let Some(body) = reports.recv_timeout(timeout)?{
+        let Some(report) = decode::<ExecutionReport>(Component::Users, &body) else {continue};
+        holdings.apply(&report);
+        if let Some(strategy) = strategies.get_mut(&report.client_id){strategy.on_fill(&report);}
+        match report.status{
+            OrderStatus::Rejected{reason} => logging::info(Component::User(report.client_id), format!("{}order {} for [{}] was rejected by broker {}: {}{}",
+                ANSI_BOLD_RED, report.order_id, report.stock_name, report.broker_no, reason, ANSI_RESET)).field("reason", reason),
+            OrderStatus::CancelRejected{reason} | OrderStatus::AmendRejected{reason} => logging::info(Component::User(report.client_id),
+                format!("request about {} for [{}] was refused by broker {}: {}", report.order_id, report.stock_name, report.broker_no, reason)).field("reason", reason),
+            OrderStatus::Filled | OrderStatus::PartiallyFilled => logging::info(Component::User(report.client_id), format!("{}{:?} order {} for [{}] {:?}{} by broker {} - {} units at {} ({} left, fee {:.2})",
+                ANSI_BOLD_GREEN, report.side, report.order_id, report.stock_name, report.status, ANSI_RESET,
+                report.broker_no, report.last_qty, report.last_price.round(), report.leaves_qty, report.fee))
+                .field("quantity", report.last_qty).price(report.last_price).field("leaves", report.leaves_qty).field("fee", report.fee),
+            status => logging::info(Component::User(report.client_id), format!("order {} for [{}] {:?} by broker {}", report.order_id, report.stock_name, status, report.broker_no)),
+        }.field("order_id", &report.order_id).user(report.client_id).symbol(&report.stock_name).field("broker", report.broker_no).emit();
+    }
+    Ok(())
+}
+
+// Each user's strategy
+type Strategies = HashMap<i8, Box<dyn Strategy>>;
+
+// Users thread body, returns the number of users that placed their orders
+pub fn run(transport: &dyn Transport, config: &Config, sl_rx: &Receiver<Arc<Mutex<Vec<Stock>>>>, session: &Session, rng: &mut StdRng) -> Result<i8>{
+    let behaviour = &config.users;
+    let timing = &config.timing;
+    let reports = transport.consume(&config.queues.user_reports)?;
+    let statuses = transport.subscribe(&config.queues.broker_status)?;
+    let mut router = OrderRouter::new(config.routing);
+    let mut holdings = Holdings::default();
+    let mut strategies: Strategies = (1..=config.num_users).map(|i| (i, behaviour.strategy_for(i).build())).collect();
+    let mut last_stock_list: Option<Vec<Stock>> = None;
+    let mut count_user = 0;
+    // Generate different users
+    'users: for i in 1..=config.num_users{
+        let me = Component::User(i);
+        logging::info(me, "Enter page..").emit();
+        logging::debug(me, format!("Trading with the {} strategy", strategies[&i].name())).field("strategy", strategies[&i].name()).emit();
+        logging::debug(me, "Page loading..").emit();
+        count_user+=1;
+        // make sure didn't miss out customer in the laoding page
+        loop{
+            // no stock list is coming anymore
+            if session::stop_requested() || session.ended(){break 'users;}
+            match sl_rx.try_recv(){
+                Ok(stock_list)=>{
+                    let stocks = stock_list.lock().unwrap().clone();
+                    // every strategy follows the market, not just the visiting user's
+                    for strategy in strategies.values_mut(){strategy.on_market_data(&stocks);}
+                    logging::debug(me, "Viewing the stock list").emit();
+                    logging::debug(me, "Selecting stokcs...").emit();
+                    thread::sleep(Duration::from_millis(timing.user_order_delay_ms));  
+                    let mut ctx = Context{user:i, stocks:&stocks, holdings:holdings.sellable(i), orders:holdings.open_orders(i), behaviour, rng: &mut *rng, requests:Vec::new()};
+                    let intents = strategies.get_mut(&i).unwrap().on_visit(&mut ctx);
+                    let requests = ctx.requests;
+                    send_requests(transport, config, &mut router, &mut holdings, i, requests)?;
+                    for intent in intents{
+                        thread::sleep(Duration::from_millis(timing.user_order_delay_ms));  
+                        let user_req_list = to_order(i,&intent,config);
+                        let broker_no = match intent.broker{
+                            // shares are sold through the broker holding them
+                            Some(broker_no) => {router.assign(&user_req_list.order_id, broker_no); broker_no}
+                            None => {
+                                logging::debug(me, format!("System choosing brokers ({})..",router.policy())).emit();
+                                read_statuses(&*statuses, &mut router, Duration::ZERO)?;
+                                while !router.has_brokers(){
+                                    if session::stop_requested(){break 'users;}
+                                    logging::debug(me, "Waiting for a broker").emit();
+                                    read_statuses(&*statuses, &mut router, Duration::from_millis(timing.user_page_wait_ms))?;
+                                }
+                                router.route(&user_req_list).expect("no broker to route to")
+                            }
+                        };
+                        logging::info(me, format!("{:?}{} order {} had send to broker {}..",user_req_list.side,
+                            if user_req_list.short{" short"}else{""},user_req_list.order_id,broker_no))
+                            .field("order_id", &user_req_list.order_id).user(i).symbol(&user_req_list.stock_name).field("broker", broker_no).emit();
+                        send_order(transport, config, &mut holdings, &user_req_list, broker_no)?;
+                    }
+                    last_stock_list = Some(stocks);
+                    break;
+                }
+                Err(_)=>{
+                    logging::debug(me, "Still loading").emit();
+                    // check on the orders placed so far while waiting
+                    read_reports(&*reports, &mut holdings, &mut strategies, Duration::from_millis(timing.user_page_wait_ms))?;
+                } 
+            }
+        }
+        session.user_visited();
+    }
+    // collect the fills, then each user's strategy decides what to sell before leaving
+    read_reports(&*reports, &mut holdings, &mut strategies, Duration::from_millis(timing.user_drain_ms))?;
+    if let Some(stocks) = last_stock_list.filter(|_| !session::stop_requested()){
+        for i in 1..=config.num_users{
+            let mut ctx = Context{user:i, stocks:&stocks, holdings:holdings.sellable(i), orders:holdings.open_orders(i), behaviour, rng: &mut *rng, requests:Vec::new()};
+            let intents = strategies.get_mut(&i).unwrap().on_exit(&mut ctx);
+            let requests = ctx.requests;
+            send_requests(transport, config, &mut router, &mut holdings, i, requests)?;
+            for intent in intents{
+                let sell_req = to_order(i,&intent,config);
+                let broker_no = match intent.broker{
+                    Some(broker_no) => {router.assign(&sell_req.order_id, broker_no); broker_no}
+                    None => match router.route(&sell_req){Some(broker_no) => broker_no, None => continue},
+                };
+                logging::info(Component::User(i), format!("{:?}{} order {} had send to broker {}..",sell_req.side,
+                    if sell_req.short{" short"}else{""},sell_req.order_id,broker_no))
+                    .field("order_id", &sell_req.order_id).user(i).symbol(&sell_req.stock_name).field("broker", broker_no).emit();
+                send_order(transport, config, &mut holdings, &sell_req, broker_no)?;
+            }
+        }
+    }
+    // keep following the orders until the brokers go quiet
+    read_reports(&*reports, &mut holdings, &mut strategies, Duration::from_millis(timing.user_final_drain_ms))?;
+    logging::info(Component::Users, format!("orders routed per broker {:?}", router.routed_counts())).field("routed", router.routed_counts()).emit();
+    Ok(count_user)
+}